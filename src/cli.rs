@@ -0,0 +1,289 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+use bevy::prelude::*;
+
+use bevy_lsystems::{ExportFormat, FractalPlant, LineList, SvgProjection};
+
+const USAGE: &str = "\
+Usage: bevy_lsystems headless PLANT.json [OPTIONS]
+
+Evaluates a saved plant without opening a window.
+
+Options:
+  -n, --iterations N   Derive N iterations instead of the saved count
+  -s, --stats          Print statistics about the derivation
+  -o, --output PATH    Write the plant to PATH, by its extension:
+                       .obj, .ply, .glb, .svg, or .json for the turtle's lines.
+                       May be given several times
+      --binary         Write PLY files as binary instead of ASCII
+      --view VIEW      Side an SVG is drawn from: front, side or top
+  -h, --help           Print this help";
+
+/// What `headless` was asked to do.
+#[derive(Debug, Default)]
+struct Options {
+    plant: PathBuf,
+    iterations: Option<usize>,
+    stats: bool,
+    outputs: Vec<PathBuf>,
+    binary: bool,
+    view: SvgProjection,
+}
+
+/// Runs the `headless` subcommand if it is the first argument and returns
+/// its exit code, or `None` to start the app as usual.
+pub fn run() -> Option<ExitCode> {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some("headless") {
+        return None;
+    }
+    let result = parse(args).and_then(|options| match options {
+        Some(options) => evaluate(&options),
+        None => {
+            println!("{USAGE}");
+            Ok(())
+        }
+    });
+    Some(match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    })
+}
+
+/// `None` when asked for help.
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut plant = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value\n\n{USAGE}"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-n" | "--iterations" => {
+                let n = value()?;
+                let n = n
+                    .parse()
+                    .map_err(|_| format!("not a number of iterations: {n}"))?;
+                options.iterations = Some(n);
+            }
+            "-s" | "--stats" => options.stats = true,
+            "-o" | "--output" => options.outputs.push(value()?.into()),
+            "--binary" => options.binary = true,
+            "--view" => {
+                let view = value()?;
+                options.view = SvgProjection::ALL
+                    .into_iter()
+                    .filter(|p| *p != SvgProjection::Camera)
+                    .find(|p| p.label().eq_ignore_ascii_case(&view))
+                    .ok_or(format!("unknown view: {view}"))?;
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {arg}\n\n{USAGE}")),
+            _ if plant.is_none() => plant = Some(arg.into()),
+            _ => return Err(format!("unexpected argument: {arg}\n\n{USAGE}")),
+        }
+    }
+    options.plant = plant.ok_or(format!("no plant given\n\n{USAGE}"))?;
+    Ok(Some(options))
+}
+
+fn evaluate(options: &Options) -> Result<(), String> {
+    let path = options.plant.display();
+    let json = fs::read_to_string(&options.plant).map_err(|e| format!("reading {path}: {e}"))?;
+    let mut plant: FractalPlant =
+        serde_json::from_str(&json).map_err(|e| format!("parsing {path}: {e}"))?;
+    if let Some(iterations) = options.iterations {
+        plant.lsys.iterations = iterations;
+    }
+
+    let started = Instant::now();
+    let symbols = plant
+        .lsys
+        .rules
+        .derive(&plant.lsys.iterations)
+        .map_err(|e| format!("deriving {path}: {e}"))?;
+    let derived = Instant::now();
+    let lines = plant
+        .draw(&symbols)
+        .map_err(|e| format!("drawing {path}: {e}"))?;
+    let interpreted = Instant::now();
+
+    if options.stats {
+        let (min, max) = lines.bounds().unwrap_or_default();
+        println!("plant: {}", plant.lsys.name);
+        println!("iterations: {}", plant.lsys.iterations);
+        println!("symbols: {}", symbols.len());
+        println!("segments: {}", lines.segments().len());
+        println!("triangles: {}", lines.triangles().len());
+        println!("surfaces: {}", lines.surface_count());
+        println!("bounds: {min} {max}");
+        println!("derive time: {:?}", derived - started);
+        println!("interpret time: {:?}", interpreted - derived);
+    }
+
+    for output in &options.outputs {
+        write_output(output, &plant, &lines, options)
+            .map_err(|e| format!("writing {}: {e}", output.display()))?;
+    }
+    Ok(())
+}
+
+/// What a file is written as, picked by its extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Mesh(ExportFormat),
+    Svg,
+    /// The turtle's lines, as saved with a plant.
+    Json,
+}
+
+impl Output {
+    fn from_path(path: &Path, binary: bool) -> io::Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "obj" => Ok(Output::Mesh(ExportFormat::Obj)),
+            "ply" if binary => Ok(Output::Mesh(ExportFormat::PlyBinary)),
+            "ply" => Ok(Output::Mesh(ExportFormat::PlyAscii)),
+            "glb" => Ok(Output::Mesh(ExportFormat::Glb)),
+            "svg" => Ok(Output::Svg),
+            "json" => Ok(Output::Json),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown format, use .obj, .ply, .glb, .svg or .json",
+            )),
+        }
+    }
+}
+
+fn write_output(
+    path: &Path,
+    plant: &FractalPlant,
+    lines: &LineList,
+    options: &Options,
+) -> io::Result<()> {
+    let output = Output::from_path(path, options.binary)?;
+    let mut out = BufWriter::new(File::create(path)?);
+    write_plant(&mut out, output, plant, lines, options.view)?;
+    out.flush()
+}
+
+fn write_plant(
+    out: &mut impl Write,
+    output: Output,
+    plant: &FractalPlant,
+    lines: &LineList,
+    view: SvgProjection,
+) -> io::Result<()> {
+    match output {
+        Output::Mesh(format) => plant.write_mesh(out, lines, format),
+        Output::Svg => plant.write_svg(out, lines, view.view(Quat::IDENTITY)),
+        Output::Json => Ok(serde_json::to_writer(out, lines)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Option<Options>, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_every_option() {
+        let options = parse_args(&[
+            "tree.json",
+            "-n",
+            "3",
+            "--stats",
+            "-o",
+            "tree.obj",
+            "--output",
+            "tree.svg",
+            "--binary",
+            "--view",
+            "Side",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.plant, PathBuf::from("tree.json"));
+        assert_eq!(options.iterations, Some(3));
+        assert!(options.stats);
+        assert_eq!(
+            options.outputs,
+            [PathBuf::from("tree.obj"), PathBuf::from("tree.svg")]
+        );
+        assert!(options.binary);
+        assert_eq!(options.view, SvgProjection::Side);
+
+        let plain = parse_args(&["tree.json"]).unwrap().unwrap();
+        assert_eq!(plain.iterations, None);
+        assert!(!plain.stats && !plain.binary && plain.outputs.is_empty());
+        assert_eq!(plain.view, SvgProjection::Front);
+        assert!(parse_args(&["tree.json", "--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let error = |args: &[&str]| parse_args(args).unwrap_err();
+        assert!(error(&[]).starts_with("no plant given"));
+        assert!(error(&["tree.json", "-n"]).starts_with("-n needs a value"));
+        assert!(error(&["tree.json", "-n", "many"]).starts_with("not a number"));
+        assert!(error(&["tree.json", "--view", "camera"]).starts_with("unknown view"));
+        assert!(error(&["tree.json", "--verbose"]).starts_with("unknown option: --verbose"));
+        assert!(error(&["tree.json", "bush.json"]).starts_with("unexpected argument: bush.json"));
+    }
+
+    #[test]
+    fn output_format_follows_the_extension() {
+        let output = |path: &str, binary| Output::from_path(Path::new(path), binary).ok();
+        assert_eq!(
+            output("a.obj", false),
+            Some(Output::Mesh(ExportFormat::Obj))
+        );
+        assert_eq!(
+            output("a.PLY", false),
+            Some(Output::Mesh(ExportFormat::PlyAscii))
+        );
+        assert_eq!(
+            output("a.ply", true),
+            Some(Output::Mesh(ExportFormat::PlyBinary))
+        );
+        assert_eq!(
+            output("a.glb", false),
+            Some(Output::Mesh(ExportFormat::Glb))
+        );
+        assert_eq!(output("a.svg", false), Some(Output::Svg));
+        assert_eq!(output("a.json", false), Some(Output::Json));
+        assert_eq!(output("a.txt", false), None);
+        assert_eq!(output("a", false), None);
+    }
+
+    #[test]
+    fn writes_a_plant() {
+        let plant = FractalPlant::default();
+        let symbols = plant.lsys.rules.derive(&plant.lsys.iterations).unwrap();
+        let lines = plant.draw(&symbols).unwrap();
+        let write = |output| {
+            let mut out = Vec::new();
+            write_plant(&mut out, output, &plant, &lines, SvgProjection::Front).unwrap();
+            out
+        };
+        let svg = String::from_utf8(write(Output::Svg)).unwrap();
+        assert!(svg.starts_with("<svg "));
+        let json: LineList = serde_json::from_slice(&write(Output::Json)).unwrap();
+        assert_eq!(json.segments(), lines.segments());
+        let obj = String::from_utf8(write(Output::Mesh(ExportFormat::Obj))).unwrap();
+        assert!(obj.lines().any(|l| l.starts_with("v ")));
+        assert_eq!(&write(Output::Mesh(ExportFormat::Glb))[..4], b"glTF");
+    }
+}
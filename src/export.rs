@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use bevy::asset::UntypedAssetId;
+use bevy::ecs::system::Command;
+use bevy::prelude::*;
+use bevy::render::mesh::{PrimitiveTopology, VertexAttributeValues};
+
+use crate::foliage::{builtin_surfaces, polygon_mesh};
+use crate::fractal_plant::{FractalPlant, LineList, PlantLines};
+use crate::glb::{Glb, GlbMaterial, LINES, TRIANGLES};
+use crate::lsys_rendering::LineMaterial;
+use crate::svg::{write_svg, SvgProjection};
+use crate::wind::SwayingMaterial;
+
+/// File formats a plant can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Wavefront OBJ, with `l` elements for line plants and faces for tubes
+    /// and leaves.
+    Obj,
+    PlyAscii,
+    /// Little endian binary PLY, smaller and faster to load than ASCII.
+    PlyBinary,
+    /// Binary glTF. From the side panel this is the whole plant entity
+    /// with its pot.
+    Glb,
+}
+
+impl ExportFormat {
+    pub(crate) const ALL: [ExportFormat; 4] = [
+        ExportFormat::Obj,
+        ExportFormat::PlyAscii,
+        ExportFormat::PlyBinary,
+        ExportFormat::Glb,
+    ];
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            ExportFormat::Obj => "OBJ",
+            ExportFormat::PlyAscii => "PLY",
+            ExportFormat::PlyBinary => "PLY (binary)",
+            ExportFormat::Glb => "GLB",
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Obj => "obj",
+            ExportFormat::PlyAscii | ExportFormat::PlyBinary => "ply",
+            ExportFormat::Glb => "glb",
+        }
+    }
+}
+
+/// A plant's branches, polygons and surfaces merged into one vertex list,
+/// with the lines and triangles indexing into it. Positions are relative
+/// to the plant's origin, Y up.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExportMesh {
+    pub(crate) positions: Vec<Vec3>,
+    /// Zero for vertices that only belong to lines.
+    pub(crate) normals: Vec<Vec3>,
+    pub(crate) colors: Vec<Color>,
+    pub(crate) lines: Vec<[u32; 2]>,
+    pub(crate) triangles: Vec<[u32; 3]>,
+}
+
+impl ExportMesh {
+    /// Everything `plant` draws for `lines`, the way it draws it: hairlines
+    /// or tubes for the branches, and the leaves and flowers in their
+    /// colors.
+    pub(crate) fn from_plant(plant: &FractalPlant, lines: &LineList) -> Self {
+        let mut export = ExportMesh::default();
+        let branches = plant.branch_mesh.build(lines);
+        export.append(&branches, Transform::IDENTITY, plant.branch_color);
+        if let Some(polygons) = polygon_mesh(&lines.triangles, &lines.triangle_colors) {
+            export.append(&polygons, Transform::IDENTITY, plant.leaf_color);
+        }
+        let surfaces = builtin_surfaces();
+        for instance in &lines.surfaces {
+            let Some((_, mesh, color)) = surfaces.iter().find(|(s, _, _)| *s == instance.symbol)
+            else {
+                continue;
+            };
+            let transform = Transform {
+                translation: instance.position,
+                rotation: instance.rotation,
+                scale: Vec3::splat(instance.scale),
+            };
+            export.append(mesh, transform, color.unwrap_or(plant.leaf_color));
+        }
+        export
+    }
+
+    /// Adds a line or triangle mesh placed by `transform`. Vertices without
+    /// colors of their own get `color`.
+    pub(crate) fn append(&mut self, mesh: &Mesh, transform: Transform, color: Color) {
+        let Some(positions) = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)
+            .and_then(|p| p.as_float3())
+        else {
+            return;
+        };
+        let base = self.positions.len() as u32;
+        self.positions.extend(
+            positions
+                .iter()
+                .map(|p| transform.transform_point(Vec3::from(*p))),
+        );
+        match mesh
+            .attribute(Mesh::ATTRIBUTE_NORMAL)
+            .and_then(|n| n.as_float3())
+        {
+            Some(normals) => self.normals.extend(
+                normals
+                    .iter()
+                    .map(|n| (transform.rotation * Vec3::from(*n)).normalize_or_zero()),
+            ),
+            None => self.normals.resize(self.positions.len(), Vec3::ZERO),
+        }
+        match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => self.colors.extend(
+                colors
+                    .iter()
+                    .map(|[r, g, b, a]| Color::rgba_linear(*r, *g, *b, *a)),
+            ),
+            _ => self.colors.resize(self.positions.len(), color),
+        }
+
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(indices) => indices.iter().map(|i| base + i as u32).collect(),
+            None => (base..self.positions.len() as u32).collect(),
+        };
+        match mesh.primitive_topology() {
+            PrimitiveTopology::LineList => self
+                .lines
+                .extend(indices.chunks_exact(2).map(|l| [l[0], l[1]])),
+            PrimitiveTopology::TriangleList => self
+                .triangles
+                .extend(indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]])),
+            _ => {}
+        }
+    }
+
+    /// Only written when there are faces to shade.
+    fn has_normals(&self) -> bool {
+        !self.triangles.is_empty()
+    }
+
+    /// Wavefront OBJ with the vertex colors after each position, the way
+    /// Blender reads and writes them.
+    pub(crate) fn write_obj(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "# L-system plant")?;
+        for (p, color) in self.positions.iter().zip(&self.colors) {
+            let [r, g, b, _] = color.as_rgba_f32();
+            writeln!(out, "v {} {} {} {} {} {}", p.x, p.y, p.z, r, g, b)?;
+        }
+        if self.has_normals() {
+            for n in &self.normals {
+                writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+            }
+        }
+        // OBJ counts vertices from 1.
+        for [a, b, c] in &self.triangles {
+            writeln!(out, "f {0}//{0} {1}//{1} {2}//{2}", a + 1, b + 1, c + 1)?;
+        }
+        for [a, b] in &self.lines {
+            writeln!(out, "l {} {}", a + 1, b + 1)?;
+        }
+        Ok(())
+    }
+
+    /// PLY with 8 bit sRGB vertex colors, faces for the triangles and edges
+    /// for the lines.
+    pub(crate) fn write_ply(&self, out: &mut impl Write, binary: bool) -> io::Result<()> {
+        let format = if binary {
+            "binary_little_endian"
+        } else {
+            "ascii"
+        };
+        writeln!(out, "ply")?;
+        writeln!(out, "format {format} 1.0")?;
+        writeln!(out, "comment L-system plant")?;
+        writeln!(out, "element vertex {}", self.positions.len())?;
+        let mut properties = vec!["x", "y", "z"];
+        if self.has_normals() {
+            properties.extend(["nx", "ny", "nz"]);
+        }
+        for property in properties {
+            writeln!(out, "property float {property}")?;
+        }
+        for channel in ["red", "green", "blue"] {
+            writeln!(out, "property uchar {channel}")?;
+        }
+        writeln!(out, "element face {}", self.triangles.len())?;
+        writeln!(out, "property list uchar uint vertex_indices")?;
+        writeln!(out, "element edge {}", self.lines.len())?;
+        writeln!(out, "property uint vertex1")?;
+        writeln!(out, "property uint vertex2")?;
+        writeln!(out, "end_header")?;
+
+        for i in 0..self.positions.len() {
+            let mut floats = self.positions[i].to_array().to_vec();
+            if self.has_normals() {
+                floats.extend(self.normals[i].to_array());
+            }
+            let [r, g, b, _] = self.colors[i].as_rgba_u8();
+            if binary {
+                for f in floats {
+                    out.write_all(&f.to_le_bytes())?;
+                }
+                out.write_all(&[r, g, b])?;
+            } else {
+                for f in floats {
+                    write!(out, "{f} ")?;
+                }
+                writeln!(out, "{r} {g} {b}")?;
+            }
+        }
+        for triangle in &self.triangles {
+            if binary {
+                out.write_all(&[3])?;
+                for index in triangle {
+                    out.write_all(&index.to_le_bytes())?;
+                }
+            } else {
+                writeln!(out, "3 {} {} {}", triangle[0], triangle[1], triangle[2])?;
+            }
+        }
+        for line in &self.lines {
+            if binary {
+                for index in line {
+                    out.write_all(&index.to_le_bytes())?;
+                }
+            } else {
+                writeln!(out, "{} {}", line[0], line[1])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// GLB with a single node, the vertex colors baked in and the lines
+    /// unlit.
+    pub(crate) fn write_glb(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut glb = Glb::default();
+        let mut primitives = Vec::new();
+        if !self.triangles.is_empty() {
+            let indices: Vec<u32> = self.triangles.iter().flatten().copied().collect();
+            let (vertices, indices) = compact(&indices);
+            let positions: Vec<Vec3> = vertices.iter().map(|&v| self.positions[v]).collect();
+            let normals: Vec<Vec3> = vertices.iter().map(|&v| self.normals[v]).collect();
+            let colors = self.linear_colors(&vertices);
+            let attributes = glb.attributes(&positions, Some(&normals), None, Some(&colors));
+            let material = glb.material(GlbMaterial {
+                double_sided: true,
+                ..default()
+            });
+            primitives.push(glb.primitive(attributes, &indices, TRIANGLES, material));
+        }
+        if !self.lines.is_empty() {
+            // Line vertices have no normals, so they get their own accessors.
+            let indices: Vec<u32> = self.lines.iter().flatten().copied().collect();
+            let (vertices, indices) = compact(&indices);
+            let positions: Vec<Vec3> = vertices.iter().map(|&v| self.positions[v]).collect();
+            let colors = self.linear_colors(&vertices);
+            let attributes = glb.attributes(&positions, None, None, Some(&colors));
+            let material = glb.material(GlbMaterial::unlit(Color::WHITE));
+            primitives.push(glb.primitive(attributes, &indices, LINES, material));
+        }
+        let mesh = (!primitives.is_empty()).then(|| glb.mesh(primitives));
+        let root = glb.node(None, &Transform::IDENTITY, mesh, Vec::new());
+        glb.write(out, root)
+    }
+
+    fn linear_colors(&self, vertices: &[usize]) -> Vec<[f32; 4]> {
+        vertices
+            .iter()
+            .map(|&v| self.colors[v].as_linear_rgba_f32())
+            .collect()
+    }
+
+    pub(crate) fn write(&self, out: &mut impl Write, format: ExportFormat) -> io::Result<()> {
+        match format {
+            ExportFormat::Obj => self.write_obj(out),
+            ExportFormat::PlyAscii => self.write_ply(out, false),
+            ExportFormat::PlyBinary => self.write_ply(out, true),
+            ExportFormat::Glb => self.write_glb(out),
+        }
+    }
+
+    /// Writes the mesh as `<name>.<extension>` next to the saved
+    /// configurations and returns the path.
+    pub(crate) fn write_to_file(&self, name: &str, format: ExportFormat) -> io::Result<PathBuf> {
+        write_file(name, format.extension(), |out| self.write(out, format))
+    }
+}
+
+fn write_file(
+    name: &str,
+    extension: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<PathBuf> {
+    let path = PathBuf::from(format!("{name}.{extension}"));
+    let mut out = BufWriter::new(File::create(&path)?);
+    write(&mut out)?;
+    out.flush()?;
+    Ok(path)
+}
+
+/// The vertices `indices` refer to, in order of first use, and `indices`
+/// renumbered to count within them.
+fn compact(indices: &[u32]) -> (Vec<usize>, Vec<u32>) {
+    let mut vertices = Vec::new();
+    let mut renumbered = HashMap::new();
+    let indices = indices
+        .iter()
+        .map(|&i| {
+            *renumbered.entry(i).or_insert_with(|| {
+                vertices.push(i as usize);
+                vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+    (vertices, indices)
+}
+
+/// glTF meshes already written, by the Bevy mesh and material they were
+/// written for.
+type MeshCache = HashMap<(AssetId<Mesh>, Option<UntypedAssetId>), Option<usize>>;
+
+/// Adds `entity` and everything below it as glTF nodes, each with the mesh
+/// it is drawn with in the color of its material. Entities drawing the same
+/// mesh in the same material share one glTF mesh.
+fn add_entity_nodes(
+    glb: &mut Glb,
+    meshes: &mut MeshCache,
+    world: &World,
+    entity: Entity,
+    transform: &Transform,
+) -> usize {
+    let children: Vec<usize> = world
+        .get::<Children>(entity)
+        .into_iter()
+        .flatten()
+        .map(|&child| {
+            let transform = world.get::<Transform>(child).copied().unwrap_or_default();
+            add_entity_nodes(glb, meshes, world, child, &transform)
+        })
+        .collect();
+    let mesh = world.get::<Handle<Mesh>>(entity).and_then(|handle| {
+        let mesh = world.resource::<Assets<Mesh>>().get(handle)?;
+        let (material_id, material) = entity_material(world, entity, mesh);
+        *meshes
+            .entry((handle.id(), material_id))
+            .or_insert_with(|| glb.add_mesh(mesh, material))
+    });
+    let name = world.get::<Name>(entity).map(Name::as_str);
+    glb.node(name, transform, mesh, children)
+}
+
+fn entity_material(
+    world: &World,
+    entity: Entity,
+    mesh: &Mesh,
+) -> (Option<UntypedAssetId>, GlbMaterial) {
+    fn asset<A: Asset>(world: &World, entity: Entity) -> Option<(UntypedAssetId, &A)> {
+        let handle = world.get::<Handle<A>>(entity)?;
+        let material = world.get_resource::<Assets<A>>()?.get(handle)?;
+        Some((handle.id().untyped(), material))
+    }
+    if let Some((id, material)) = asset::<StandardMaterial>(world, entity) {
+        (Some(id), GlbMaterial::from(material))
+    } else if let Some((id, material)) = asset::<SwayingMaterial>(world, entity) {
+        (Some(id), GlbMaterial::from(&material.base))
+    } else if let Some((id, material)) = asset::<LineMaterial>(world, entity) {
+        // The line shader ignores the material color for vertex colors,
+        // glTF multiplies them.
+        if mesh.contains_attribute(Mesh::ATTRIBUTE_COLOR) {
+            (Some(id), GlbMaterial::unlit(Color::WHITE))
+        } else {
+            (Some(id), GlbMaterial::unlit(material.color()))
+        }
+    } else {
+        (None, GlbMaterial::default())
+    }
+}
+
+/// Exports a plant's last finished geometry, named after its L-system.
+pub(crate) struct ExportPlant {
+    pub(crate) entity: Entity,
+    pub(crate) format: ExportFormat,
+}
+
+impl Command for ExportPlant {
+    fn apply(self, world: &mut World) {
+        let Some(plant) = world.get::<FractalPlant>(self.entity) else {
+            return;
+        };
+        let Some(PlantLines(lines)) = world.get::<PlantLines>(self.entity) else {
+            warn!("{} has no geometry to export yet", plant.lsys.name);
+            return;
+        };
+        let result = match self.format {
+            // The pot and the nodes' transforms only exist in the world.
+            // The plant itself goes at the origin.
+            ExportFormat::Glb => write_file(&plant.lsys.name, "glb", |out| {
+                let mut glb = Glb::default();
+                let root = add_entity_nodes(
+                    &mut glb,
+                    &mut MeshCache::new(),
+                    world,
+                    self.entity,
+                    &Transform::IDENTITY,
+                );
+                glb.write(out, root)
+            }),
+            format => ExportMesh::from_plant(plant, lines).write_to_file(&plant.lsys.name, format),
+        };
+        match result {
+            Ok(path) => info!("Exported {} to {}", plant.lsys.name, path.display()),
+            Err(e) => error!("Exporting {} failed: {e}", plant.lsys.name),
+        }
+    }
+}
+
+/// Draws a plant's last finished lines flat into `<name>.svg`.
+pub(crate) struct ExportPlantSvg {
+    pub(crate) entity: Entity,
+    pub(crate) projection: SvgProjection,
+}
+
+impl Command for ExportPlantSvg {
+    fn apply(self, world: &mut World) {
+        let camera = world
+            .query_filtered::<&GlobalTransform, With<Camera3d>>()
+            .iter(world)
+            .next()
+            .map_or(Quat::IDENTITY, |camera| camera.compute_transform().rotation);
+        let Some(plant) = world.get::<FractalPlant>(self.entity) else {
+            return;
+        };
+        let Some(PlantLines(lines)) = world.get::<PlantLines>(self.entity) else {
+            warn!("{} has no geometry to export yet", plant.lsys.name);
+            return;
+        };
+        let plant_rotation = world
+            .get::<GlobalTransform>(self.entity)
+            .map_or(Quat::IDENTITY, |plant| plant.compute_transform().rotation);
+        let view = self.projection.view(plant_rotation.inverse() * camera);
+        let result = write_file(&plant.lsys.name, "svg", |out| {
+            write_svg(out, lines, view, plant.branch_color, plant.leaf_color)
+        });
+        match result {
+            Ok(path) => info!("Exported {} to {}", plant.lsys.name, path.display()),
+            Err(e) => error!("Exporting {} failed: {e}", plant.lsys.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fractal_plant::BranchMesh;
+
+    /// The default `1[-0]+0` plant, drawn with `branch_mesh`.
+    fn default_plant(branch_mesh: BranchMesh) -> (ExportMesh, usize) {
+        let plant = FractalPlant {
+            branch_mesh,
+            ..Default::default()
+        };
+        let lines = plant.lines();
+        (ExportMesh::from_plant(&plant, &lines), lines.lines.len())
+    }
+
+    fn count(text: &str, prefix: &str) -> usize {
+        text.lines().filter(|l| l.starts_with(prefix)).count()
+    }
+
+    #[test]
+    fn obj_lines_round_trip() {
+        let (export, segments) = default_plant(BranchMesh::Lines);
+        assert!(segments > 0);
+        assert_eq!(export.lines.len(), segments);
+        let mut out = Vec::new();
+        export.write_obj(&mut out).unwrap();
+        let obj = String::from_utf8(out).unwrap();
+        assert_eq!(count(&obj, "v "), export.positions.len());
+        assert_eq!(count(&obj, "vn "), 0);
+        assert_eq!(count(&obj, "f "), 0);
+        assert_eq!(count(&obj, "l "), segments);
+        // OBJ indices start at 1.
+        assert!(obj.contains("\nl 1 2\n"));
+    }
+
+    #[test]
+    fn obj_tubes_round_trip() {
+        let sides = 4;
+        let (export, segments) = default_plant(BranchMesh::Tubes { sides });
+        assert_eq!(export.triangles.len(), segments * sides * 2);
+        let mut out = Vec::new();
+        export.write_obj(&mut out).unwrap();
+        let obj = String::from_utf8(out).unwrap();
+        assert_eq!(count(&obj, "v "), export.positions.len());
+        assert_eq!(count(&obj, "vn "), export.positions.len());
+        assert_eq!(count(&obj, "f "), export.triangles.len());
+        assert_eq!(count(&obj, "l "), 0);
+    }
+
+    #[test]
+    fn ascii_ply_counts() {
+        let (export, segments) = default_plant(BranchMesh::Lines);
+        let mut out = Vec::new();
+        export.write_ply(&mut out, false).unwrap();
+        let ply = String::from_utf8(out).unwrap();
+        let (header, body) = ply.split_once("end_header\n").unwrap();
+        assert!(header.starts_with("ply\nformat ascii 1.0\n"));
+        let vertices = export.positions.len();
+        assert!(header.contains(&format!("element vertex {vertices}\n")));
+        assert!(header.contains("element face 0\n"));
+        assert!(header.contains(&format!("element edge {segments}\n")));
+        assert!(!header.contains("property float nx"));
+        assert_eq!(body.lines().count(), vertices + segments);
+        // x y z red green blue
+        assert_eq!(body.lines().next().unwrap().split(' ').count(), 6);
+        assert_eq!(
+            body.lines().last().unwrap(),
+            format!("{} {}", vertices - 2, vertices - 1)
+        );
+    }
+
+    #[test]
+    fn binary_ply_layout() {
+        let sides = 3;
+        let (export, segments) = default_plant(BranchMesh::Tubes { sides });
+        let mut out = Vec::new();
+        export.write_ply(&mut out, true).unwrap();
+        let end = b"end_header\n";
+        let header_len = out.windows(end.len()).position(|w| w == end).unwrap() + end.len();
+        let header = std::str::from_utf8(&out[..header_len]).unwrap();
+        assert!(header.starts_with("ply\nformat binary_little_endian 1.0\n"));
+        assert!(header.contains("property float nz\nproperty uchar red\n"));
+        let faces = segments * sides * 2;
+        assert!(header.contains(&format!("element face {faces}\n")));
+        assert!(header.contains("element edge 0\n"));
+
+        // Six floats and three color bytes per vertex, then a count byte
+        // and three indices per face.
+        let vertex_size = 6 * 4 + 3;
+        let vertices = &out[header_len..header_len + export.positions.len() * vertex_size];
+        let body = &out[header_len..];
+        assert_eq!(body.len(), vertices.len() + faces * (1 + 3 * 4));
+        let float = |at: usize| f32::from_le_bytes(vertices[at..at + 4].try_into().unwrap());
+        let first = export.positions[0];
+        assert_eq!([float(0), float(4), float(8)], first.to_array());
+        let [r, g, b, _] = export.colors[0].as_rgba_u8();
+        assert_eq!(&vertices[24..27], &[r, g, b]);
+        let faces = &body[vertices.len()..];
+        assert_eq!(faces[0], 3);
+        let index = |at: usize| u32::from_le_bytes(faces[at..at + 4].try_into().unwrap());
+        assert_eq!([index(1), index(5), index(9)], export.triangles[0]);
+    }
+
+    #[test]
+    fn glb_lines_and_triangles_have_their_own_vertices() {
+        let (mut export, segments) = default_plant(BranchMesh::Lines);
+        let triangle = polygon_mesh(&[[Vec3::ZERO, Vec3::X, Vec3::Y]], &[]).unwrap();
+        export.append(&triangle, Transform::IDENTITY, Color::GREEN);
+        let mut out = Vec::new();
+        export.write_glb(&mut out).unwrap();
+        let json_len = u32::from_le_bytes(out[12..16].try_into().unwrap()) as usize;
+        let json: serde_json::Value = serde_json::from_slice(&out[20..20 + json_len]).unwrap();
+        let count = |accessor: &serde_json::Value| {
+            json["accessors"][accessor.as_u64().unwrap() as usize]["count"].as_u64()
+        };
+        let primitives = json["meshes"][0]["primitives"].as_array().unwrap();
+        assert_eq!(primitives.len(), 2);
+        let (triangles, lines) = (&primitives[0]["attributes"], &primitives[1]["attributes"]);
+        assert_eq!(count(&triangles["POSITION"]), Some(3));
+        assert_eq!(count(&triangles["NORMAL"]), Some(3));
+        assert_eq!(count(&lines["POSITION"]), Some(2 * segments as u64));
+        assert!(lines.get("NORMAL").is_none());
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
+use bevy::render::render_asset::RenderAssetUsages;
+
+use crate::fractal_plant::SurfaceInstance;
+use crate::wind::{SwayingMaterial, WindExtension};
+
+/// Marks the polygon and surface entities spawned as children of a plant,
+/// so they can be replaced when the plant is rebuilt.
+#[derive(Component)]
+pub(crate) struct PlantFoliage;
+
+/// Materials of the predefined surfaces that grammars place with `~`: `L`
+/// is a leaf and `K` a flower, as in "The Algorithmic Beauty of Plants".
+/// Shared by all plants.
+#[derive(Resource, Default)]
+pub(crate) struct SurfaceMaterials {
+    /// Surfaces with a color of their own, the others are drawn with the
+    /// plant's leaf material.
+    pub(crate) colored: HashMap<char, Handle<SwayingMaterial>>,
+    /// Polygons colored from a palette are drawn with this instead of the
+    /// plant's leaf material, which would tint them.
+    pub(crate) vertex_color_material: Handle<SwayingMaterial>,
+}
+
+/// The predefined surfaces by symbol, with their own color or `None` for
+/// the plant's leaf color.
+pub(crate) fn builtin_surfaces() -> [(char, Mesh, Option<Color>); 2] {
+    [
+        ('L', leaf_mesh(), None),
+        ('K', flower_mesh(), Some(Color::rgb(0.95, 0.6, 0.8))),
+    ]
+}
+
+pub fn setup_surface_materials(
+    mut surfaces: ResMut<SurfaceMaterials>,
+    mut materials: ResMut<Assets<SwayingMaterial>>,
+) {
+    for (symbol, _, color) in builtin_surfaces() {
+        if let Some(color) = color {
+            let material = materials.add(foliage_material(color));
+            surfaces.colored.insert(symbol, material);
+        }
+    }
+    surfaces.vertex_color_material = materials.add(foliage_material(Color::WHITE));
+}
+
+/// Leaves and petals are single polygons, so draw both sides. They sway
+/// with the branches they grow on.
+pub(crate) fn foliage_material(color: Color) -> SwayingMaterial {
+    SwayingMaterial {
+        base: StandardMaterial {
+            base_color: color,
+            double_sided: true,
+            cull_mode: None,
+            perceptual_roughness: 0.8,
+            ..default()
+        },
+        extension: WindExtension::default(),
+    }
+}
+
+/// Every instance of each predefined surface merged into one mesh in the
+/// plant's space, so that the wind bends them with the branches.
+pub(crate) fn surface_meshes(instances: &[SurfaceInstance]) -> Vec<(char, Mesh)> {
+    builtin_surfaces()
+        .into_iter()
+        .filter_map(|(symbol, mesh, _)| {
+            let transforms: Vec<Transform> = instances
+                .iter()
+                .filter(|instance| instance.symbol == symbol)
+                .map(|instance| Transform {
+                    translation: instance.position,
+                    rotation: instance.rotation,
+                    scale: Vec3::splat(instance.scale),
+                })
+                .collect();
+            (!transforms.is_empty()).then(|| (symbol, merge_instances(&mesh, &transforms)))
+        })
+        .collect()
+}
+
+/// Copies of a triangle `mesh` placed by each of `transforms`.
+fn merge_instances(mesh: &Mesh, transforms: &[Transform]) -> Mesh {
+    let positions = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|p| p.as_float3())
+        .unwrap_or_default();
+    let normals = mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(|n| n.as_float3())
+        .unwrap_or_default();
+    let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+        Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs),
+        _ => None,
+    };
+    let indices: Vec<u32> = match mesh.indices() {
+        Some(indices) => indices.iter().map(|i| i as u32).collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+
+    let mut merged_positions = Vec::with_capacity(positions.len() * transforms.len());
+    let mut merged_normals = Vec::with_capacity(merged_positions.capacity());
+    let mut merged_indices = Vec::with_capacity(indices.len() * transforms.len());
+    for transform in transforms {
+        let base = merged_positions.len() as u32;
+        merged_positions.extend(
+            positions
+                .iter()
+                .map(|p| transform.transform_point(Vec3::from(*p))),
+        );
+        merged_normals.extend(
+            normals
+                .iter()
+                .map(|n| (transform.rotation * Vec3::from(*n)).normalize_or_zero()),
+        );
+        merged_indices.extend(indices.iter().map(|i| base + i));
+    }
+
+    let merged = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, merged_positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, merged_normals)
+    .with_inserted_indices(Indices::U32(merged_indices));
+    match uvs {
+        Some(uvs) => {
+            merged.with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs.repeat(transforms.len()))
+        }
+        None => merged,
+    }
+}
+
+/// Spawns the polygons and surfaces of a plant as children of `plant`.
+pub(crate) fn spawn_foliage(
+    commands: &mut Commands,
+    plant: Entity,
+    polygons: Option<(Handle<Mesh>, Handle<SwayingMaterial>)>,
+    surfaces: Vec<(char, Handle<Mesh>)>,
+    surface_materials: &SurfaceMaterials,
+    leaf_material: &Handle<SwayingMaterial>,
+) {
+    let surfaces = surfaces.into_iter().map(|(symbol, mesh)| {
+        let material = surface_materials
+            .colored
+            .get(&symbol)
+            .unwrap_or(leaf_material)
+            .clone();
+        (mesh, material)
+    });
+    commands.entity(plant).with_children(|parent| {
+        for (mesh, material) in polygons.into_iter().chain(surfaces) {
+            parent.spawn((
+                MaterialMeshBundle {
+                    mesh,
+                    material,
+                    ..default()
+                },
+                PlantFoliage,
+            ));
+        }
+    });
+}
+
+/// Triangles from `{ . }` polygons with flat normals, or `None` if there
+/// are none. `colors` holds one palette color per triangle, if any.
+pub(crate) fn polygon_mesh(triangles: &[[Vec3; 3]], colors: &[Color]) -> Option<Mesh> {
+    if triangles.is_empty() {
+        return None;
+    }
+    let positions: Vec<Vec3> = triangles.iter().flatten().copied().collect();
+    let normals: Vec<Vec3> = triangles
+        .iter()
+        .flat_map(|[a, b, c]| {
+            let normal = (*b - *a).cross(*c - *a).normalize_or_zero();
+            [normal; 3]
+        })
+        .collect();
+
+    let mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    if !colors.is_empty() && colors.len() == triangles.len() {
+        let colors: Vec<[f32; 4]> = colors
+            .iter()
+            .flat_map(|c| [c.as_linear_rgba_f32(); 3])
+            .collect();
+        Some(mesh.with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors))
+    } else {
+        Some(mesh)
+    }
+}
+
+/// A unit long leaf along +Y in the XY plane, facing +Z.
+fn leaf_mesh() -> Mesh {
+    let outline = [
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.15, 0.25, 0.0),
+        Vec3::new(0.2, 0.5, 0.0),
+        Vec3::new(0.12, 0.8, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        Vec3::new(-0.12, 0.8, 0.0),
+        Vec3::new(-0.2, 0.5, 0.0),
+        Vec3::new(-0.15, 0.25, 0.0),
+    ];
+    let triangles: Vec<[Vec3; 3]> = (1..outline.len() - 1)
+        .map(|i| [outline[0], outline[i], outline[i + 1]])
+        .collect();
+    let uvs: Vec<Vec2> = triangles
+        .iter()
+        .flatten()
+        .map(|p| Vec2::new(p.x + 0.5, 1.0 - p.y))
+        .collect();
+
+    polygon_mesh(&triangles, &[])
+        .expect("the leaf outline has triangles")
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+}
+
+/// Five petals of unit radius opening around +Y.
+fn flower_mesh() -> Mesh {
+    const PETALS: usize = 5;
+    let mut positions = vec![Vec3::ZERO];
+    let mut indices = Vec::<u32>::new();
+    for i in 0..PETALS {
+        let angle = i as f32 / PETALS as f32 * TAU;
+        let dir = Vec3::new(angle.cos(), 0.3, angle.sin());
+        let side = Vec3::new(-angle.sin(), 0.0, angle.cos()) * 0.25;
+        let base = positions.len() as u32;
+        positions.extend([dir * 0.5 + side, dir, dir * 0.5 - side]);
+        indices.extend_from_slice(&[0, base, base + 1, 0, base + 1, base + 2]);
+    }
+    let normals = vec![Vec3::Y; positions.len()];
+
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_indices(Indices::U32(indices))
+}
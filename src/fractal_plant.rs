@@ -3,83 +3,67 @@ use bevy::prelude::*;
 use bevy::render::color::Color;
 
 use bevy::render::mesh::PrimitiveTopology;
+use bevy::render::primitives::Aabb;
 use bevy::render::render_asset::RenderAssetUsages;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use bevy::tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task};
 use bevy_egui::egui::Color32;
+use std::collections::HashSet;
 use std::f32::consts::PI;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError};
 
 use bevy::sprite::MaterialMesh2dBundle;
 
-use crate::lsys_egui::SideMenuOptions;
-use crate::lsys_rendering::{FractalPlantUpdateEvent, LineMaterial};
+use crate::export::{ExportFormat, ExportMesh, ExportPlant, ExportPlantSvg};
+use crate::foliage::{
+    foliage_material, polygon_mesh, spawn_foliage, surface_meshes, PlantFoliage, SurfaceMaterials,
+};
+use crate::lifecycle::Lifecycle;
+use crate::lsys_rendering::{FractalPlantUpdateEvent, LineMaterial, ATTRIBUTE_GROWTH};
 use crate::lsys_rendering::{GenerateLineList, LineMesh};
-use crate::pickup::ActiveEntityCandidate;
 use crate::save_load;
+use crate::svg::{self, SvgProjection};
+use crate::tube_mesh::tube_mesh;
+use crate::turtle::{
+    BranchWidth, ColorGradient, GradientMode, Interpretation, Tropism, Turtle, TurtleCommand,
+    TurtleState,
+};
+use crate::wind::{SwayingMaterial, WindExtension};
 
 use crate::lsystems::LSysDrawer;
 
 use crate::lsystems::LSysRules;
+use crate::lsystems::LSystemEvaluationError;
+use crate::lsystems::ParametricRule;
+use crate::lsystems::SymbolBuffer;
 
 use crate::lsystems::LSys;
 
-pub fn add_first_fractal_plant(
-    mut commands: Commands,
-    mut materials: ResMut<Assets<LineMaterial>>,
-    mut update_writer: EventWriter<FractalPlantUpdateEvent>,
-    assets: Res<AssetServer>,
-) {
-    let pot: Handle<Scene> = assets.load("pot.glb#Scene0");
-
-    let tree = FractalPlant::default();
-    let plant_mesh = LineMesh::default();
-    let plant_mesh_handle = plant_mesh.mesh_handle.clone();
-    let id = commands
-        .spawn((tree, plant_mesh))
-        .insert(LSysDrawer { changed: true })
-        .insert(ActiveEntityCandidate)
-        .insert(MaterialMeshBundle {
-            material: materials.add(LineMaterial::new(Color::rgb(1.0, 1.0, 1.0))),
-            mesh: plant_mesh_handle,
-            ..Default::default()
-        })
-        .insert(SceneBundle {
-            scene: pot,
-            ..default()
-        })
-        .id();
-    update_writer.send(FractalPlantUpdateEvent::MESH(id));
-    update_writer.send(FractalPlantUpdateEvent::MATERIAL(id));
-}
 pub fn add_new_fractal_plants(
     mut commands: Commands,
     mut materials: ResMut<Assets<LineMaterial>>,
     mut update_writer: EventWriter<FractalPlantUpdateEvent>,
     spawn_q: Query<(Entity, &PlantSpawnPoint)>,
-    assets: Res<AssetServer>,
 ) {
     for (entity, PlantSpawnPoint(pos)) in spawn_q.iter() {
-        let pot: Handle<Scene> = assets.load("pot.glb#Scene0");
-
-        let tree = FractalPlant::default();
+        let mut tree = FractalPlant::default();
+        tree.lsys.rules.seed = entity.to_bits();
         let plant_mesh = LineMesh::default();
         let plant_mesh_handle = plant_mesh.mesh_handle.clone();
         let id = commands
             .spawn(Transform::from_translation(pos.clone()))
             .insert(LSysDrawer { changed: true })
             .insert((tree))
-            .insert(ActiveEntityCandidate)
             .insert(MaterialMeshBundle {
                 material: materials.add(LineMaterial::new(Color::rgb(1.0, 1.0, 1.0))),
                 mesh: plant_mesh_handle,
 
                 ..Default::default()
             })
-            .insert(SceneBundle {
-                scene: pot,
-                ..default()
-            })
             .id();
         update_writer.send(FractalPlantUpdateEvent::MESH(id));
         update_writer.send(FractalPlantUpdateEvent::MATERIAL(id));
@@ -87,23 +71,108 @@ pub fn add_new_fractal_plants(
     }
 }
 
+/// Plants that don't have the material they are drawn with yet.
+type NewPlantFilter = (Added<FractalPlant>, Without<Handle<LineMaterial>>);
+
+/// Gives plants spawned on their own, as in
+/// `commands.spawn(FractalPlant::new(..))`, the material and mesh they are
+/// drawn with.
+pub fn set_up_fractal_plants(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<LineMaterial>>,
+    mut update_writer: EventWriter<FractalPlantUpdateEvent>,
+    new_plants: Query<(Entity, Option<&Transform>), NewPlantFilter>,
+) {
+    for (entity, transform) in new_plants.iter() {
+        commands.entity(entity).insert(MaterialMeshBundle {
+            material: materials.add(LineMaterial::new(Color::rgb(1.0, 1.0, 1.0))),
+            transform: transform.copied().unwrap_or_default(),
+            ..Default::default()
+        });
+        update_writer.send(FractalPlantUpdateEvent::MESH(entity));
+        update_writer.send(FractalPlantUpdateEvent::MATERIAL(entity));
+    }
+}
+
+/// Spawn one to add a plant with the default grammar.
 #[derive(Component)]
 pub struct PlantSpawnPoint(pub Vec3);
 
 #[derive(Component, Serialize, Deserialize, Debug)]
-pub(crate) struct FractalPlant {
+pub struct FractalPlant {
     pub(crate) start_pos: Vec3,
     pub(crate) start_angle: f32,
     pub(crate) turn_angle: f32,
     pub(crate) line_length: f32,
     pub(crate) branch_color: Color,
-    pub(crate) lsys: LSys,
+    #[serde(default = "default_leaf_color")]
+    pub(crate) leaf_color: Color,
+    /// Colors selected with `'`; when empty the plant is drawn in
+    /// `branch_color` and `leaf_color`.
+    #[serde(default)]
+    pub(crate) palette: Vec<Color>,
+    #[serde(default)]
+    pub(crate) gradient: ColorGradient,
+    pub lsys: LSys,
+    #[serde(default)]
+    pub(crate) tropism: Tropism,
+    #[serde(default)]
+    pub(crate) branch_width: BranchWidth,
+    #[serde(default)]
+    pub(crate) branch_mesh: BranchMesh,
+    #[serde(default)]
+    pub(crate) lifecycle: Lifecycle,
     #[serde(skip_serializing, skip_deserializing)]
     pub mesh_handle: Handle<Mesh>,
     #[serde(skip_serializing, skip_deserializing)]
     pub material_handle: Handle<LineMaterial>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) tube_material_handle: Handle<SwayingMaterial>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) leaf_material_handle: Handle<SwayingMaterial>,
+    /// Mesh of the plant's `{ . }` polygons, drawn by a foliage child.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) polygon_mesh_handle: Handle<Mesh>,
+    /// Why the last mesh update failed, shown in the side panel.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) eval_error: Option<String>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) growth: PlantGrowth,
+    /// View picked for SVG export in the side panel.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) svg_projection: SvgProjection,
+}
+
+/// Growth animation of a plant, revealing its segments generation by
+/// generation.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PlantGrowth {
+    /// From 0 for a seed to 1 for the full plant.
+    pub(crate) progress: f32,
+    pub(crate) playing: bool,
+    /// Seconds from seed to full plant.
+    pub(crate) duration: f32,
+    /// Progress the branch materials show, `None` after a rebuild or when
+    /// the materials are new.
+    shown: Option<f32>,
 }
 
+impl Default for PlantGrowth {
+    fn default() -> Self {
+        Self {
+            progress: 1.0,
+            playing: false,
+            duration: 5.0,
+            shown: None,
+        }
+    }
+}
+
+/// The lines from a plant's last finished mesh task, kept for redrawing
+/// the plant partly grown and for exporting it.
+#[derive(Component, Default)]
+pub(crate) struct PlantLines(pub(crate) LineList);
+
 impl FractalPlant {
     pub fn new(
         start_pos: Vec3,
@@ -131,6 +200,9 @@ impl Default for FractalPlant {
             start_angle: 0.0,
             line_length: 0.1,
             branch_color: Color::rgb(1.0, 1.0, 0.0),
+            leaf_color: default_leaf_color(),
+            palette: Vec::new(),
+            gradient: ColorGradient::default(),
             lsys: LSys {
                 name: "fractal_tree".to_string(),
                 rules: LSysRules::new(
@@ -138,100 +210,468 @@ impl Default for FractalPlant {
                     vec![('1', "11".to_string()), ('0', "1[-0]+0".to_string())],
                 ),
                 iterations: 2,
+                interpretation: Default::default(),
+                cache: Default::default(),
+                prediction: Default::default(),
             },
+            tropism: Tropism::default(),
+            branch_width: BranchWidth::default(),
+            branch_mesh: BranchMesh::default(),
+            lifecycle: Lifecycle::default(),
             mesh_handle: Handle::<Mesh>::default(),
             material_handle: Handle::<LineMaterial>::default(),
+            tube_material_handle: Handle::<SwayingMaterial>::default(),
+            leaf_material_handle: Handle::<SwayingMaterial>::default(),
+            polygon_mesh_handle: Handle::<Mesh>::default(),
+            eval_error: None,
+            growth: PlantGrowth::default(),
+            svg_projection: SvgProjection::default(),
         };
 
         plant
     }
 }
 
+/// How branches are turned into a mesh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) enum BranchMesh {
+    /// Hairlines drawn with `LineMaterial`. Branch widths only show as tubes.
+    #[default]
+    Lines,
+    /// Lit tubes drawn with `StandardMaterial`, with `sides` corners around.
+    Tubes { sides: usize },
+}
+
+fn default_leaf_color() -> Color {
+    Color::rgb(0.3, 0.6, 0.2)
+}
+
+/// Everything a mesh task builds for a plant.
+pub(crate) struct PlantGeometry {
+    lines: LineList,
+    branches: Mesh,
+    polygons: Option<Mesh>,
+    surfaces: Vec<(char, Mesh)>,
+}
+
+impl BranchMesh {
+    pub(crate) fn build(&self, lines: &LineList) -> Mesh {
+        match *self {
+            BranchMesh::Lines => Mesh::from(lines.clone()),
+            BranchMesh::Tubes { sides } => tube_mesh(lines, sides),
+        }
+    }
+}
+
+/// Result of a mesh task; `None` if it was cancelled before finishing.
+type PlantMeshResult = Option<Result<PlantGeometry, LSystemEvaluationError>>;
+
+/// The fields of a `FractalPlant` its mesh is built from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeometrySnapshot {
+    start_pos: Vec3,
+    start_angle: f32,
+    turn_angle: f32,
+    line_length: f32,
+    palette: Vec<Color>,
+    gradient: ColorGradient,
+    rules: LSysRules,
+    iterations: usize,
+    interpretation: Interpretation,
+    tropism: Tropism,
+    branch_width: BranchWidth,
+    branch_mesh: BranchMesh,
+}
+
+/// Derivation and turtle interpretation of a plant running on the
+/// `AsyncComputeTaskPool`. The plant keeps its previous mesh until the task
+/// finishes.
+#[derive(Component)]
+pub(crate) struct PlantMeshTask {
+    task: Task<PlantMeshResult>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl FractalPlant {
+    /// Everything that goes into the mesh, to tell edits that need a `MESH`
+    /// update from those that only need a `MATERIAL` one.
+    pub fn geometry_snapshot(&self) -> GeometrySnapshot {
+        GeometrySnapshot {
+            start_pos: self.start_pos,
+            start_angle: self.start_angle,
+            turn_angle: self.turn_angle,
+            line_length: self.line_length,
+            palette: self.palette.clone(),
+            gradient: self.gradient.clone(),
+            rules: self.lsys.rules.clone(),
+            // Aging only changes the geometry through the iterations.
+            iterations: self.lsys.iterations,
+            interpretation: self.lsys.interpretation.clone(),
+            tropism: self.tropism,
+            branch_width: self.branch_width,
+            branch_mesh: self.branch_mesh,
+        }
+    }
+
+    /// Everything that goes into the materials.
+    pub fn appearance(&self) -> (Color, Color, bool) {
+        (self.branch_color, self.leaf_color, self.has_vertex_colors())
+    }
+
+    /// Whether the mesh carries its own colors from the palette or
+    /// gradient instead of using `branch_color`.
+    pub(crate) fn has_vertex_colors(&self) -> bool {
+        !self.palette.is_empty() || self.gradient.mode != GradientMode::Off
+    }
+
+    /// The turtle for this plant, copied so that it can be interpreted off
+    /// the main thread.
+    pub(crate) fn turtle(&self) -> Turtle {
+        Turtle {
+            start: TurtleState::from_rotation(
+                self.start_pos,
+                Quat::from_rotation_z(self.start_angle),
+                self.branch_width.base,
+            ),
+            step: self.line_length,
+            angle: self.turn_angle,
+            max_segments: self.lsys.rules.limits.max_segments,
+            interpretation: self.lsys.interpretation.clone(),
+            tropism: self.tropism,
+            width_taper: self.branch_width.taper,
+            palette: self.palette.clone(),
+            gradient: self.gradient.clone(),
+        }
+    }
+
+    /// Draws a derivation of this plant's rules with its turtle, e.g. one
+    /// returned by `self.lsys.rules.derive(&self.lsys.iterations)`.
+    pub fn draw(&self, symbols: &SymbolBuffer) -> Result<LineList, LSystemEvaluationError> {
+        self.turtle()
+            .interpret(symbols, None)
+            .expect("only a cancelled interpretation has no result")
+    }
+
+    /// Writes the plant drawn as `lines` the way it is shown, with tubes,
+    /// leaves and colors, relative to its origin.
+    pub fn write_mesh(
+        &self,
+        out: &mut impl Write,
+        lines: &LineList,
+        format: ExportFormat,
+    ) -> io::Result<()> {
+        ExportMesh::from_plant(self, lines).write(out, format)
+    }
+
+    /// Draws the plant's `lines` flat as seen from `view`, see
+    /// `SvgProjection::view`.
+    pub fn write_svg(&self, out: &mut impl Write, lines: &LineList, view: Quat) -> io::Result<()> {
+        svg::write_svg(out, lines, view, self.branch_color, self.leaf_color)
+    }
+}
+
+#[cfg(test)]
+impl FractalPlant {
+    /// Derives and draws the plant in one go, as the mesh task does.
+    pub(crate) fn lines(&self) -> LineList {
+        let symbols = self.lsys.rules.derive(&self.lsys.iterations).unwrap();
+        self.draw(&symbols).unwrap()
+    }
+}
+
+/// Starts a mesh task for every plant with a `MESH` update, cancelling the
+/// one that was still running for it.
 pub fn update_plant_meshes(
-    mut query: Query<(Entity, &mut FractalPlant), Changed<FractalPlant>>,
-    mut meshes: ResMut<Assets<Mesh>>,
+    query: Query<(&FractalPlant, Option<&PlantMeshTask>)>,
+    mut mesh_updates: EventReader<FractalPlantUpdateEvent>,
     mut commands: Commands,
 ) {
-    for (entity, mut plant) in query.iter_mut() {
-        let mut new_line_list = Vec::<(Vec3, Vec3)>::new();
-        let start_pos = plant.start_pos;
-        let mut v_pos = vec![start_pos];
-        let iterations = plant.lsys.iterations;
-
-        let mut pos = start_pos;
-        let mut pos_stack: Vec<Vec3> = Vec::new();
-        pos_stack.push(start_pos);
-        let mut heading: Quat = Quat::from_rotation_z(plant.start_angle);
-        let mut angle_stack: Vec<Quat> = Vec::new();
-        angle_stack.push(heading);
-        let branch_length = plant.line_length;
+    let pool = AsyncComputeTaskPool::get();
+    let mut updated = HashSet::new();
+    for update in mesh_updates.read() {
+        let FractalPlantUpdateEvent::MESH(entity) = *update else {
+            continue;
+        };
+        if !updated.insert(entity) {
+            continue;
+        }
+        let Ok((plant, running)) = query.get(entity) else {
+            continue;
+        };
+        if let Some(running) = running {
+            running.cancelled.store(true, Ordering::Relaxed);
+        }
 
-        let evaluated_lsystem = plant.lsys.rules.eval(&iterations).unwrap_or("".to_string());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let task_cancelled = cancelled.clone();
+        let cache = plant.lsys.cache.clone();
+        let rules = plant.lsys.rules.clone();
+        let iterations = plant.lsys.iterations;
+        let turtle = plant.turtle();
+        let branch_mesh = plant.branch_mesh;
+        let task = pool.spawn(async move {
+            // A cancelled task may still hold the cache, wait for it.
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            if task_cancelled.load(Ordering::Relaxed) {
+                return None;
+            }
+            let line_list = match cache.derive(&rules, iterations, Some(&task_cancelled))? {
+                Ok(symbols) => turtle.interpret(symbols, Some(&task_cancelled))?,
+                Err(e) => Err(e),
+            };
+            Some(line_list.map(|line_list| PlantGeometry {
+                polygons: polygon_mesh(&line_list.triangles, &line_list.triangle_colors),
+                surfaces: surface_meshes(&line_list.surfaces),
+                branches: branch_mesh.build(&line_list),
+                lines: line_list,
+            }))
+        });
+        // Replacing the component drops, and with that cancels, the old task.
+        commands
+            .entity(entity)
+            .insert(PlantMeshTask { task, cancelled });
+    }
+}
 
-        for c in evaluated_lsystem.chars() {
-            match c {
-                '1' => {
-                    let new_pos = heading.mul_vec3(Vec3::new(0.0, branch_length, 0.0)) + pos;
-                    new_line_list.push((pos, new_pos));
+/// Swaps in the meshes of finished tasks.
+pub fn finish_plant_meshes(
+    mut query: Query<(
+        Entity,
+        &mut FractalPlant,
+        &mut PlantMeshTask,
+        Option<&Children>,
+    )>,
+    foliage: Query<(), With<PlantFoliage>>,
+    surface_materials: Res<SurfaceMaterials>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands,
+) {
+    for (entity, mut plant, mut running, children) in query.iter_mut() {
+        let Some(result) = block_on(future::poll_once(&mut running.task)) else {
+            continue;
+        };
+        commands.entity(entity).remove::<PlantMeshTask>();
 
-                    pos = new_pos;
-                }
-                '0' => {
-                    let new_pos = heading.mul_vec3(Vec3::new(0.0, branch_length, 0.0)) + pos;
-                    new_line_list.push((pos, new_pos));
-                }
-                '[' => {
-                    pos_stack.push(pos);
-                    angle_stack.push(heading);
+        match result {
+            None => {}
+            Some(Err(e)) => plant.eval_error = Some(e.to_string()),
+            Some(Ok(geometry)) => {
+                plant.eval_error = None;
+                // The new mesh may have more generations, have
+                // animate_plant_growth work out how many are left.
+                plant.growth.shown = None;
+                // Old leaves go, the pot's scene stays.
+                for &child in children.into_iter().flatten() {
+                    if foliage.contains(child) {
+                        commands.entity(child).despawn_recursive();
+                    }
                 }
-                '-' => {
-                    heading *= Quat::from_rotation_z(-plant.turn_angle);
-                }
-                '+' => {
-                    heading *= Quat::from_rotation_z(plant.turn_angle);
-                }
-                '<' => {
-                    heading *= Quat::from_rotation_x(-plant.turn_angle);
-                }
-                '>' => {
-                    heading *= Quat::from_rotation_x(plant.turn_angle);
+                let polygons = geometry.polygons.map(|mesh| {
+                    let material = if mesh.contains_attribute(Mesh::ATTRIBUTE_COLOR) {
+                        surface_materials.vertex_color_material.clone()
+                    } else {
+                        plant.leaf_material_handle.clone()
+                    };
+                    let (handle, _) =
+                        replace_mesh(&mut meshes, &mut plant.polygon_mesh_handle, mesh);
+                    (handle, material)
+                });
+                let surfaces = geometry
+                    .surfaces
+                    .into_iter()
+                    .map(|(symbol, mesh)| (symbol, meshes.add(mesh)))
+                    .collect();
+                spawn_foliage(
+                    &mut commands,
+                    entity,
+                    polygons,
+                    surfaces,
+                    &surface_materials,
+                    &plant.leaf_material_handle,
+                );
+                commands.entity(entity).insert(PlantLines(geometry.lines));
+
+                let mesh = geometry.branches;
+                let tubes = mesh.primitive_topology() == PrimitiveTopology::TriangleList;
+                // Meshes don't carry a transform, so the local bounds are
+                // the entity's Aabb.
+                let aabb = mesh.compute_aabb();
+                let (handle, added) = replace_mesh(&mut meshes, &mut plant.mesh_handle, mesh);
+                let mut entity = commands.entity(entity);
+                if added {
+                    entity.remove::<Handle<Mesh>>().insert(handle);
                 }
-                ']' => {
-                    pos = pos_stack.pop().unwrap_or(pos);
-                    v_pos.push(pos);
-                    heading = angle_stack.pop().unwrap_or(heading);
+                match aabb {
+                    Some(aabb) => entity.insert(aabb),
+                    None => entity.remove::<Aabb>(),
+                };
+                // Switch materials together with the mesh, neither material
+                // can draw the other's topology.
+                if tubes {
+                    entity
+                        .remove::<Handle<LineMaterial>>()
+                        .insert(plant.tube_material_handle.clone());
+                } else {
+                    entity
+                        .remove::<Handle<SwayingMaterial>>()
+                        .insert(plant.material_handle.clone());
                 }
-                _ => {}
             }
         }
+    }
+}
 
-        let handle = meshes.add(LineList {
-            lines: new_line_list,
-        });
-        plant.mesh_handle = handle.clone();
-        commands
-            .entity(entity)
-            .remove::<Handle<Mesh>>()
-            .remove::<bevy::render::primitives::Aabb>()
-            .insert(handle.clone());
+/// Advances playing growth animations and tells the branch materials of
+/// plants whose progress changed how far to grow. Foliage only appears on
+/// the full plant.
+pub fn animate_plant_growth(
+    time: Res<Time>,
+    mut query: Query<(&mut FractalPlant, &PlantLines, Option<&Children>)>,
+    mut foliage: Query<&mut Visibility, With<PlantFoliage>>,
+    mut line_materials: ResMut<Assets<LineMaterial>>,
+    mut tube_materials: ResMut<Assets<SwayingMaterial>>,
+) {
+    for (mut plant, lines, children) in query.iter_mut() {
+        let plant = &mut *plant;
+        let growth = &mut plant.growth;
+        if growth.playing {
+            growth.progress += time.delta_seconds() / growth.duration.max(0.01);
+            if growth.progress >= 1.0 {
+                growth.progress = 1.0;
+                growth.playing = false;
+            }
+        }
+        if growth.shown == Some(growth.progress) {
+            continue;
+        }
+        let full = growth.progress >= 1.0;
+        let growth_left = (1.0 - growth.progress.clamp(0.0, 1.0)) * lines.0.generations() as f32;
+        if let Some(material) = line_materials.get_mut(&plant.material_handle) {
+            material.growth_left = growth_left;
+        }
+        if let Some(material) = tube_materials.get_mut(&plant.tube_material_handle) {
+            material.extension.growth_left = growth_left;
+        }
+        growth.shown = Some(growth.progress);
+
+        let visibility = if full {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        for &child in children.into_iter().flatten() {
+            if let Ok(mut child_visibility) = foliage.get_mut(child) {
+                *child_visibility = visibility;
+            }
+        }
+    }
+}
+
+/// Replaces the mesh behind `handle` in place, or adds it if `handle`
+/// doesn't point to a mesh yet. Returns the handle and whether it is new.
+fn replace_mesh(
+    meshes: &mut Assets<Mesh>,
+    handle: &mut Handle<Mesh>,
+    mesh: Mesh,
+) -> (Handle<Mesh>, bool) {
+    match meshes.get_mut(handle.id()) {
+        Some(existing) => {
+            *existing = mesh;
+            (handle.clone(), false)
+        }
+        None => {
+            *handle = meshes.add(mesh);
+            (handle.clone(), true)
+        }
     }
 }
 
+/// Brings the materials of every plant with a `MATERIAL` update in line
+/// with its colors.
 pub fn update_plant_materials(
-    mut query: Query<(Entity, &mut FractalPlant), Changed<FractalPlant>>,
+    mut query: Query<(&mut FractalPlant, Has<Handle<SwayingMaterial>>)>,
     mut mats: ResMut<Assets<LineMaterial>>,
+    mut swaying_mats: ResMut<Assets<SwayingMaterial>>,
     mut material_updates: EventReader<FractalPlantUpdateEvent>,
     mut commands: Commands,
 ) {
-    for (entity, mut plant) in query.iter_mut() {
-        let new_material = LineMaterial::new(plant.branch_color);
-        plant.material_handle = mats.add(new_material);
-        commands
-            .entity(entity)
-            .remove::<Handle<LineMaterial>>()
-            .insert(plant.material_handle.clone());
+    let mut updated = HashSet::new();
+    for update in material_updates.read() {
+        let FractalPlantUpdateEvent::MATERIAL(entity) = *update else {
+            continue;
+        };
+        if !updated.insert(entity) {
+            continue;
+        }
+        let Ok((mut plant, has_tubes)) = query.get_mut(entity) else {
+            continue;
+        };
+        // Vertex colors are multiplied with the base color.
+        let tube_color = if plant.has_vertex_colors() {
+            Color::WHITE
+        } else {
+            plant.branch_color
+        };
+        // Each plant owns one material of each kind and edits it in place,
+        // so only a plant that has none yet needs its handle inserted.
+        let mut added = false;
+        match mats.get_mut(&plant.material_handle) {
+            Some(material) => material.set_color(plant.branch_color),
+            None => {
+                plant.material_handle = mats.add(LineMaterial::new(plant.branch_color));
+                added = true;
+            }
+        }
+        match swaying_mats.get_mut(&plant.tube_material_handle) {
+            Some(material) => material.base.base_color = tube_color,
+            None => {
+                plant.tube_material_handle = swaying_mats.add(SwayingMaterial {
+                    base: StandardMaterial {
+                        base_color: tube_color,
+                        perceptual_roughness: 0.9,
+                        ..default()
+                    },
+                    extension: WindExtension::default(),
+                });
+                added = true;
+            }
+        }
+        match swaying_mats.get_mut(&plant.leaf_material_handle) {
+            Some(leaf_material) => leaf_material.base.base_color = plant.leaf_color,
+            None => {
+                plant.leaf_material_handle = swaying_mats.add(foliage_material(plant.leaf_color))
+            }
+        }
+        // The material type follows the mesh, which finish_plant_meshes
+        // swaps in once it is built.
+        if !added {
+            continue;
+        }
+        // New materials start fully grown.
+        plant.growth.shown = None;
+        if has_tubes {
+            commands
+                .entity(entity)
+                .remove::<Handle<SwayingMaterial>>()
+                .insert(plant.tube_material_handle.clone());
+        } else {
+            commands
+                .entity(entity)
+                .remove::<Handle<LineMaterial>>()
+                .insert(plant.material_handle.clone());
+        }
     }
-    material_updates.clear();
+}
+
+/// Something that can be edited in the side panel while it is the active
+/// entity.
+pub trait SideMenuOptions {
+    fn side_menu_options(
+        &mut self,
+        ui: &mut bevy_egui::egui::Ui,
+        id: Entity,
+        commands: &mut Commands,
+    );
 }
 
 impl SideMenuOptions for FractalPlant {
@@ -275,6 +715,46 @@ impl SideMenuOptions for FractalPlant {
                 0.0..=0.3,
             ));
         });
+        ui.horizontal(|ui| {
+            ui.label("Branch Width");
+            ui.add(bevy_egui::egui::Slider::new(
+                &mut self.branch_width.base,
+                0.0..=0.1,
+            ));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Width Taper");
+            ui.add(bevy_egui::egui::Slider::new(
+                &mut self.branch_width.taper,
+                0.1..=1.0,
+            ));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Branches");
+            let tubes = matches!(self.branch_mesh, BranchMesh::Tubes { .. });
+            if ui.radio(!tubes, "Lines").clicked() {
+                self.branch_mesh = BranchMesh::Lines;
+            }
+            if ui.radio(tubes, "Tubes").clicked() && !tubes {
+                self.branch_mesh = BranchMesh::Tubes { sides: 8 };
+            }
+            if let BranchMesh::Tubes { sides } = &mut self.branch_mesh {
+                ui.add(bevy_egui::egui::Slider::new(sides, 3..=32).text("sides"));
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Tropism");
+            ui.add(bevy_egui::egui::DragValue::new(&mut self.tropism.direction.x).speed(0.05));
+            ui.add(bevy_egui::egui::DragValue::new(&mut self.tropism.direction.y).speed(0.05));
+            ui.add(bevy_egui::egui::DragValue::new(&mut self.tropism.direction.z).speed(0.05));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Susceptibility");
+            ui.add(bevy_egui::egui::Slider::new(
+                &mut self.tropism.susceptibility,
+                -1.0..=1.0,
+            ));
+        });
         ui.horizontal(|ui| {
             ui.label("Iterations");
             ui.add(bevy_egui::egui::Slider::new(
@@ -282,6 +762,96 @@ impl SideMenuOptions for FractalPlant {
                 0..=6,
             ));
         });
+        ui.horizontal(|ui| {
+            let lifecycle = &mut self.lifecycle;
+            if ui
+                .checkbox(&mut lifecycle.enabled, "Grow over time")
+                .changed()
+            {
+                // Pick up from the current iterations instead of a seed.
+                lifecycle.age = lifecycle.age_at(self.lsys.iterations);
+            }
+            if lifecycle.enabled {
+                ui.label(format!("age {:.0} s", lifecycle.age));
+            }
+        });
+        if self.lifecycle.enabled {
+            ui.horizontal(|ui| {
+                ui.label("Seconds per iteration");
+                ui.add(
+                    bevy_egui::egui::DragValue::new(&mut self.lifecycle.seconds_per_iteration)
+                        .clamp_range(1.0..=3600.0),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label("Mature at");
+                ui.add(bevy_egui::egui::Slider::new(
+                    &mut self.lifecycle.mature_iterations,
+                    0..=6,
+                ));
+            });
+            if self.lsys.iterations != old_iterations {
+                self.lifecycle.age = self.lifecycle.age_at(self.lsys.iterations);
+            }
+        }
+        if let Some(lengths) = self
+            .lsys
+            .prediction
+            .predict(&self.lsys.rules, self.lsys.iterations)
+        {
+            let estimate = if self.lsys.rules.has_exact_prediction() {
+                ""
+            } else {
+                "~"
+            };
+            ui.label(format!(
+                "Predicted symbols: {}{:.0}",
+                estimate,
+                lengths.last().copied().unwrap_or(0.0)
+            ));
+        }
+        ui.horizontal(|ui| {
+            ui.label("Symbol budget");
+            ui.add(
+                bevy_egui::egui::DragValue::new(&mut self.lsys.rules.limits.max_symbols)
+                    .speed(1000.0),
+            );
+        });
+        ui.horizontal(|ui| {
+            ui.label("Segment budget");
+            ui.add(
+                bevy_egui::egui::DragValue::new(&mut self.lsys.rules.limits.max_segments)
+                    .speed(1000.0),
+            );
+        });
+        if let Some(error) = &self.eval_error {
+            ui.colored_label(Color32::RED, error);
+        }
+        ui.horizontal(|ui| {
+            ui.label("Growth");
+            let label = if self.growth.playing { "Pause" } else { "Play" };
+            if ui.button(label).clicked() {
+                if !self.growth.playing && self.growth.progress >= 1.0 {
+                    self.growth.progress = 0.0;
+                }
+                self.growth.playing = !self.growth.playing;
+            }
+            if ui
+                .add(bevy_egui::egui::Slider::new(
+                    &mut self.growth.progress,
+                    0.0..=1.0,
+                ))
+                .dragged()
+            {
+                self.growth.playing = false;
+            }
+            ui.add(
+                bevy_egui::egui::DragValue::new(&mut self.growth.duration)
+                    .clamp_range(0.1..=120.0)
+                    .speed(0.1)
+                    .suffix(" s"),
+            );
+        });
         let mut col = Color32::from_rgb(
             (self.branch_color.r() * 255.0) as u8,
             (self.branch_color.g() * 255.0) as u8,
@@ -300,6 +870,96 @@ impl SideMenuOptions for FractalPlant {
             );
             mat_changed = true;
         }
+        ui.horizontal(|ui| {
+            ui.label("Gradient");
+            ui.radio_value(&mut self.gradient.mode, GradientMode::Off, "Off");
+            ui.radio_value(&mut self.gradient.mode, GradientMode::Depth, "Depth");
+            ui.radio_value(&mut self.gradient.mode, GradientMode::Distance, "Distance");
+        });
+        if self.gradient.mode != GradientMode::Off {
+            let mut removed = None;
+            for (i, (pos, color)) in self.gradient.stops.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.add(bevy_egui::egui::Slider::new(pos, 0.0..=1.0));
+                    let mut col = Color32::from_rgb(
+                        (color.r() * 255.0) as u8,
+                        (color.g() * 255.0) as u8,
+                        (color.b() * 255.0) as u8,
+                    );
+                    let old_col = col;
+                    ui.color_edit_button_srgba(&mut col);
+                    if col != old_col {
+                        *color = Color::rgb(
+                            col.r() as f32 / 255.0,
+                            col.g() as f32 / 255.0,
+                            col.b() as f32 / 255.0,
+                        );
+                    }
+                    if ui.button("x").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = removed {
+                self.gradient.stops.remove(i);
+            }
+            if ui.button("Add color stop").clicked() {
+                let t = 0.5;
+                let color = self.gradient.sample(t);
+                self.gradient.stops.push((t, color));
+            }
+        }
+
+        let mut leaf_col = Color32::from_rgb(
+            (self.leaf_color.r() * 255.0) as u8,
+            (self.leaf_color.g() * 255.0) as u8,
+            (self.leaf_color.b() * 255.0) as u8,
+        );
+        let old_leaf_col = leaf_col;
+        ui.horizontal(|ui| {
+            ui.label("Leaf Color");
+            ui.color_edit_button_srgba(&mut leaf_col);
+        });
+        if leaf_col != old_leaf_col {
+            self.leaf_color = Color::rgb(
+                leaf_col.r() as f32 / 255.0,
+                leaf_col.g() as f32 / 255.0,
+                leaf_col.b() as f32 / 255.0,
+            );
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Palette");
+            let mut removed = None;
+            for (i, color) in self.palette.iter_mut().enumerate() {
+                let mut col = Color32::from_rgb(
+                    (color.r() * 255.0) as u8,
+                    (color.g() * 255.0) as u8,
+                    (color.b() * 255.0) as u8,
+                );
+                let old_col = col;
+                let response = ui.color_edit_button_srgba(&mut col);
+                if col != old_col {
+                    *color = Color::rgb(
+                        col.r() as f32 / 255.0,
+                        col.g() as f32 / 255.0,
+                        col.b() as f32 / 255.0,
+                    );
+                }
+                if response.secondary_clicked() {
+                    removed = Some(i);
+                }
+            }
+            if let Some(i) = removed {
+                self.palette.remove(i);
+            }
+            if ui.button("+").clicked() {
+                let last = self.palette.last().copied().unwrap_or(self.branch_color);
+                self.palette.push(last);
+            }
+        })
+        .response
+        .on_hover_text("' selects the next color, right click removes one");
 
         ui.label("Rules:");
         for (i, (k, v)) in self.lsys.rules.rules.iter_mut().enumerate() {
@@ -312,6 +972,89 @@ impl SideMenuOptions for FractalPlant {
                 if old_v != *v {}
             });
         }
+        let mut removed_rule = None;
+        for (i, (k, successors)) in self.lsys.rules.stochastic_rules.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label("Stochastic rule:");
+                let mut new_symbol = k.to_string();
+                ui.add(bevy_egui::egui::TextEdit::singleline(&mut new_symbol).desired_width(16.0));
+                // Typing after the current symbol replaces it.
+                if let Some(c) = new_symbol.chars().last() {
+                    *k = c;
+                }
+                if ui.button("x").clicked() {
+                    removed_rule = Some(i);
+                }
+            });
+            let mut removed = None;
+            for (j, (weight, v)) in successors.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        bevy_egui::egui::DragValue::new(weight)
+                            .clamp_range(0.0..=10.0)
+                            .speed(0.05),
+                    );
+                    ui.label(" -> ");
+                    ui.text_edit_singleline(v);
+                    if ui.button("x").clicked() {
+                        removed = Some(j);
+                    }
+                });
+            }
+            if let Some(j) = removed {
+                successors.remove(j);
+            }
+            if ui.button("Add successor").clicked() {
+                successors.push((1.0, k.to_string()));
+            }
+        }
+        if let Some(i) = removed_rule {
+            self.lsys.rules.stochastic_rules.remove(i);
+        }
+        if ui.button("Add stochastic rule").clicked() {
+            self.lsys
+                .rules
+                .stochastic_rules
+                .push(('X', vec![(1.0, "X".to_string())]));
+        }
+        let mut removed_rule = None;
+        for (i, rule) in self.lsys.rules.parametric_rules.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut rule.left_context);
+                ui.label("<");
+                ui.text_edit_singleline(&mut rule.predecessor);
+                ui.label(">");
+                ui.text_edit_singleline(&mut rule.right_context);
+                ui.label(":");
+                ui.text_edit_singleline(&mut rule.condition);
+                ui.label(" -> ");
+                ui.text_edit_singleline(&mut rule.successor);
+                if ui.button("x").clicked() {
+                    removed_rule = Some(i);
+                }
+            });
+        }
+        if let Some(i) = removed_rule {
+            self.lsys.rules.parametric_rules.remove(i);
+        }
+        if ui.button("Add parametric rule").clicked() {
+            self.lsys
+                .rules
+                .parametric_rules
+                .push(ParametricRule::default());
+        }
+        let mut new_ignore = self.lsys.rules.ignore.iter().collect::<String>();
+        ui.horizontal(|ui| {
+            ui.label("Context ignores");
+            ui.text_edit_singleline(&mut new_ignore);
+        });
+        if new_ignore != self.lsys.rules.ignore.iter().collect::<String>() {
+            self.lsys.rules.ignore = new_ignore.chars().collect();
+        }
+        ui.horizontal(|ui| {
+            ui.label("Seed");
+            ui.add(bevy_egui::egui::DragValue::new(&mut self.lsys.rules.seed));
+        });
         ui.label("Axiom:");
         let mut new_axiom = self.lsys.rules.axiom.clone().iter().collect::<String>();
         ui.text_edit_singleline(&mut new_axiom);
@@ -319,6 +1062,40 @@ impl SideMenuOptions for FractalPlant {
             self.lsys.rules.axiom = new_axiom.chars().collect();
         }
 
+        ui.label("Turtle commands:");
+        let mut removed = None;
+        for (i, (symbol, command)) in self.lsys.interpretation.commands.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                let mut new_symbol = symbol.to_string();
+                ui.add(bevy_egui::egui::TextEdit::singleline(&mut new_symbol).desired_width(16.0));
+                // Typing after the current symbol replaces it.
+                if let Some(c) = new_symbol.chars().last() {
+                    *symbol = c;
+                }
+                bevy_egui::egui::ComboBox::from_id_source(("turtle_command", i))
+                    .selected_text(command.label())
+                    .show_ui(ui, |ui| {
+                        for option in TurtleCommand::ALL {
+                            ui.selectable_value(command, option, option.label());
+                        }
+                    });
+                if ui.button("x").clicked() {
+                    removed = Some(i);
+                }
+            });
+        }
+        if let Some(i) = removed {
+            self.lsys.interpretation.commands.remove(i);
+        }
+        if ui.button("Add turtle command").clicked() {
+            if let Some(symbol) = self.lsys.interpretation.unused_symbol() {
+                self.lsys
+                    .interpretation
+                    .commands
+                    .push((symbol, TurtleCommand::Ignore));
+            }
+        }
+
         ui.label("Name");
         let mut new_name = self.lsys.name.clone();
         ui.text_edit_singleline(&mut new_name);
@@ -328,6 +1105,36 @@ impl SideMenuOptions for FractalPlant {
         if ui.button("Save configuration").clicked() {
             let _ = save_load::serialize_to_file(&self, &self.lsys.name.clone());
         }
+        ui.horizontal(|ui| {
+            ui.label("Export");
+            for format in ExportFormat::ALL {
+                if ui.button(format.label()).clicked() {
+                    commands.add(ExportPlant {
+                        entity: active_id,
+                        format,
+                    });
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            bevy_egui::egui::ComboBox::from_id_source("svg_projection")
+                .selected_text(self.svg_projection.label())
+                .show_ui(ui, |ui| {
+                    for projection in SvgProjection::ALL {
+                        ui.selectable_value(
+                            &mut self.svg_projection,
+                            projection,
+                            projection.label(),
+                        );
+                    }
+                });
+            if ui.button("SVG").clicked() {
+                commands.add(ExportPlantSvg {
+                    entity: active_id,
+                    projection: self.svg_projection,
+                });
+            }
+        });
         if ui.button("Load configuration").clicked() {
             let loaded: FractalPlant = save_load::deserialize_from_file(&self.lsys.name.clone())
                 .unwrap_or(FractalPlant::default());
@@ -337,6 +1144,13 @@ impl SideMenuOptions for FractalPlant {
             self.turn_angle = loaded.turn_angle;
             self.line_length = loaded.line_length;
             self.branch_color = loaded.branch_color;
+            self.leaf_color = loaded.leaf_color;
+            self.palette = loaded.palette;
+            self.gradient = loaded.gradient;
+            self.tropism = loaded.tropism;
+            self.branch_width = loaded.branch_width;
+            self.branch_mesh = loaded.branch_mesh;
+            self.lifecycle = loaded.lifecycle;
             self.lsys = loaded.lsys;
             mat_changed = true;
         }
@@ -346,19 +1160,119 @@ impl SideMenuOptions for FractalPlant {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct LineList {
     pub(crate) lines: Vec<(Vec3, Vec3)>,
+    /// Width of each line, empty for curves that don't have one.
+    #[serde(default)]
+    pub(crate) widths: Vec<f32>,
+    /// Generation each line is born in while the plant grows, the number of
+    /// segments between it and the root.
+    #[serde(default)]
+    pub(crate) births: Vec<u32>,
+    /// Color at the start and end of each line, empty when drawn without a
+    /// palette or gradient.
+    #[serde(default)]
+    pub(crate) colors: Vec<[Color; 2]>,
+    /// Triangulated `{ . }` polygons.
+    #[serde(default)]
+    pub(crate) triangles: Vec<[Vec3; 3]>,
+    /// Color of each triangle, empty when drawn without a palette or
+    /// gradient.
+    #[serde(default)]
+    pub(crate) triangle_colors: Vec<Color>,
+    /// Predefined surfaces placed with `~`.
+    #[serde(default)]
+    pub(crate) surfaces: Vec<SurfaceInstance>,
+}
+
+/// A predefined surface such as the leaf in `~L`, placed in the turtle's
+/// frame and scaled by its parameter or the step length.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct SurfaceInstance {
+    pub(crate) symbol: char,
+    pub(crate) position: Vec3,
+    pub(crate) rotation: Quat,
+    pub(crate) scale: f32,
+}
+
+impl LineList {
+    pub fn segments(&self) -> &[(Vec3, Vec3)] {
+        &self.lines
+    }
+
+    pub fn triangles(&self) -> &[[Vec3; 3]] {
+        &self.triangles
+    }
+
+    /// Number of leaves, flowers and other predefined surfaces placed.
+    pub fn surface_count(&self) -> usize {
+        self.surfaces.len()
+    }
+
+    /// Corners of the box around every line and polygon, `None` when
+    /// nothing is drawn.
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        self.lines
+            .iter()
+            .flat_map(|(a, b)| [*a, *b])
+            .chain(self.triangles.iter().flatten().copied())
+            .fold(None, |bounds, p| match bounds {
+                Some((min, max)) => Some((p.min(min), p.max(max))),
+                None => Some((p, p)),
+            })
+    }
+
+    /// Number of generations the plant grows in, one for lines without
+    /// births.
+    pub(crate) fn generations(&self) -> u32 {
+        self.births.iter().max().map_or(1, |max| max + 1)
+    }
+
+    /// How many generations before the last one each line is born, `None`
+    /// for lines without births.
+    pub(crate) fn generations_before_last(&self) -> Option<Vec<f32>> {
+        if self.births.len() != self.lines.len() {
+            return None;
+        }
+        let last = self.generations() - 1;
+        Some(self.births.iter().map(|b| (last - b) as f32).collect())
+    }
 }
 
 impl From<LineList> for Mesh {
     fn from(line: LineList) -> Self {
+        // Both ends of a line grow from its start.
+        let growth: Option<Vec<_>> = line.generations_before_last().map(|before_last| {
+            line.lines
+                .iter()
+                .zip(before_last)
+                .flat_map(|((a, _), g)| [a.extend(g).to_array(); 2])
+                .collect()
+        });
         let vertices: Vec<_> = line.lines.into_iter().flat_map(|(a, b)| [a, b]).collect();
+        let colors: Vec<_> = line
+            .colors
+            .into_iter()
+            .flat_map(|[a, b]| [a.as_linear_rgba_f32(), b.as_linear_rgba_f32()])
+            .collect();
+        let has_colors = !colors.is_empty() && colors.len() == vertices.len();
 
-        Mesh::new(
+        let mesh = Mesh::new(
             // This tells wgpu that the positions are list of lines
             // where every pair is a start and end point
             PrimitiveTopology::LineList,
-            RenderAssetUsages::RENDER_WORLD,
+            // Plant meshes stay in the main world, to be replaced in place
+            // and exported.
+            RenderAssetUsages::default(),
         )
         // Add the vertices positions as an attribute
-        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vertices)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vertices);
+        let mesh = match growth {
+            Some(growth) => mesh.with_inserted_attribute(ATTRIBUTE_GROWTH, growth),
+            None => mesh,
+        };
+        if has_colors {
+            mesh.with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)
+        } else {
+            mesh
+        }
     }
 }
@@ -0,0 +1,320 @@
+use std::io::{self, Write};
+
+use bevy::prelude::*;
+use bevy::render::mesh::{PrimitiveTopology, VertexAttributeValues};
+use serde_json::{json, Map, Value};
+
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+pub(crate) const LINES: u32 = 1;
+pub(crate) const TRIANGLES: u32 = 4;
+
+/// A glTF 2.0 binary (GLB) being put together: the JSON description of the
+/// scene and the single buffer its accessors point into.
+#[derive(Default)]
+pub(crate) struct Glb {
+    nodes: Vec<Value>,
+    meshes: Vec<Value>,
+    materials: Vec<Value>,
+    accessors: Vec<Value>,
+    buffer_views: Vec<Value>,
+    buffer: Vec<u8>,
+    /// Some material uses `KHR_materials_unlit`.
+    unlit: bool,
+}
+
+/// A material in glTF's metallic-roughness model.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct GlbMaterial {
+    pub(crate) color: Color,
+    pub(crate) metallic: f32,
+    pub(crate) roughness: f32,
+    pub(crate) double_sided: bool,
+    /// Drawn in its flat color without lighting, like `LineMaterial`.
+    pub(crate) unlit: bool,
+}
+
+impl Default for GlbMaterial {
+    fn default() -> Self {
+        Self {
+            color: Color::WHITE,
+            metallic: 0.0,
+            roughness: 1.0,
+            double_sided: false,
+            unlit: false,
+        }
+    }
+}
+
+impl GlbMaterial {
+    pub(crate) fn unlit(color: Color) -> Self {
+        Self {
+            color,
+            unlit: true,
+            ..default()
+        }
+    }
+}
+
+impl From<&StandardMaterial> for GlbMaterial {
+    fn from(material: &StandardMaterial) -> Self {
+        Self {
+            color: material.base_color,
+            metallic: material.metallic,
+            roughness: material.perceptual_roughness,
+            double_sided: material.double_sided,
+            unlit: material.unlit,
+        }
+    }
+}
+
+fn float_bytes(values: impl IntoIterator<Item = f32>) -> Vec<u8> {
+    values.into_iter().flat_map(f32::to_le_bytes).collect()
+}
+
+impl Glb {
+    fn view(&mut self, bytes: &[u8], target: u32) -> usize {
+        // Floats and u32 indices have to start 4 byte aligned.
+        self.buffer.resize(self.buffer.len().next_multiple_of(4), 0);
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.buffer.len(),
+            "byteLength": bytes.len(),
+            "target": target,
+        }));
+        self.buffer.extend_from_slice(bytes);
+        self.buffer_views.len() - 1
+    }
+
+    fn accessor(&mut self, bytes: &[u8], count: usize, kind: &str, target: u32) -> usize {
+        let component_type = if target == ELEMENT_ARRAY_BUFFER {
+            UNSIGNED_INT
+        } else {
+            FLOAT
+        };
+        let view = self.view(bytes, target);
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": component_type,
+            "count": count,
+            "type": kind,
+        }));
+        self.accessors.len() - 1
+    }
+
+    /// Accessors for the vertex attributes, as the `attributes` of a
+    /// primitive. Colors are linear, as in Bevy's meshes.
+    pub(crate) fn attributes(
+        &mut self,
+        positions: &[Vec3],
+        normals: Option<&[Vec3]>,
+        uvs: Option<&[[f32; 2]]>,
+        colors: Option<&[[f32; 4]]>,
+    ) -> Map<String, Value> {
+        let mut attributes = Map::new();
+        let bytes = float_bytes(positions.iter().flat_map(|p| p.to_array()));
+        let position = self.accessor(&bytes, positions.len(), "VEC3", ARRAY_BUFFER);
+        // Viewers need the bounds of the positions.
+        let min = positions
+            .iter()
+            .copied()
+            .reduce(Vec3::min)
+            .unwrap_or_default();
+        let max = positions
+            .iter()
+            .copied()
+            .reduce(Vec3::max)
+            .unwrap_or_default();
+        self.accessors[position]["min"] = json!(min.to_array());
+        self.accessors[position]["max"] = json!(max.to_array());
+        attributes.insert("POSITION".into(), json!(position));
+
+        if let Some(normals) = normals.filter(|n| n.len() == positions.len()) {
+            let bytes = float_bytes(normals.iter().flat_map(|n| n.to_array()));
+            let normal = self.accessor(&bytes, normals.len(), "VEC3", ARRAY_BUFFER);
+            attributes.insert("NORMAL".into(), json!(normal));
+        }
+        if let Some(uvs) = uvs.filter(|uv| uv.len() == positions.len()) {
+            let bytes = float_bytes(uvs.iter().flatten().copied());
+            let uv = self.accessor(&bytes, uvs.len(), "VEC2", ARRAY_BUFFER);
+            attributes.insert("TEXCOORD_0".into(), json!(uv));
+        }
+        if let Some(colors) = colors.filter(|c| c.len() == positions.len()) {
+            let bytes = float_bytes(colors.iter().flatten().copied());
+            let color = self.accessor(&bytes, colors.len(), "VEC4", ARRAY_BUFFER);
+            attributes.insert("COLOR_0".into(), json!(color));
+        }
+        attributes
+    }
+
+    pub(crate) fn material(&mut self, material: GlbMaterial) -> usize {
+        let [r, g, b, a] = material.color.as_linear_rgba_f32();
+        let mut value = json!({
+            "pbrMetallicRoughness": {
+                "baseColorFactor": [r, g, b, a],
+                "metallicFactor": material.metallic,
+                "roughnessFactor": material.roughness,
+            },
+            "doubleSided": material.double_sided,
+        });
+        if a < 1.0 {
+            value["alphaMode"] = json!("BLEND");
+        }
+        if material.unlit {
+            value["extensions"] = json!({ "KHR_materials_unlit": {} });
+            self.unlit = true;
+        }
+        self.materials.push(value);
+        self.materials.len() - 1
+    }
+
+    /// A primitive drawing `indices` as `LINES` or `TRIANGLES`.
+    pub(crate) fn primitive(
+        &mut self,
+        attributes: Map<String, Value>,
+        indices: &[u32],
+        mode: u32,
+        material: usize,
+    ) -> Value {
+        let bytes: Vec<u8> = indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let indices = self.accessor(&bytes, indices.len(), "SCALAR", ELEMENT_ARRAY_BUFFER);
+        json!({
+            "attributes": attributes,
+            "indices": indices,
+            "mode": mode,
+            "material": material,
+        })
+    }
+
+    pub(crate) fn mesh(&mut self, primitives: Vec<Value>) -> usize {
+        self.meshes.push(json!({ "primitives": primitives }));
+        self.meshes.len() - 1
+    }
+
+    /// Adds a Bevy mesh of lines or triangles, or returns `None` if it is
+    /// empty or has another topology.
+    pub(crate) fn add_mesh(&mut self, mesh: &Mesh, material: GlbMaterial) -> Option<usize> {
+        let mode = match mesh.primitive_topology() {
+            PrimitiveTopology::LineList => LINES,
+            PrimitiveTopology::TriangleList => TRIANGLES,
+            _ => return None,
+        };
+        let positions: Vec<Vec3> = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)?
+            .as_float3()?
+            .iter()
+            .map(|p| Vec3::from(*p))
+            .collect();
+        // glTF doesn't allow empty accessors.
+        if positions.is_empty() {
+            return None;
+        }
+        let normals: Option<Vec<Vec3>> = mesh
+            .attribute(Mesh::ATTRIBUTE_NORMAL)
+            .and_then(|n| n.as_float3())
+            .map(|n| n.iter().map(|n| Vec3::from(*n)).collect());
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs.as_slice()),
+            _ => None,
+        };
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => Some(colors.as_slice()),
+            _ => None,
+        };
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(indices) => indices.iter().map(|i| i as u32).collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let attributes = self.attributes(&positions, normals.as_deref(), uvs, colors);
+        let material = self.material(material);
+        let primitive = self.primitive(attributes, &indices, mode, material);
+        Some(self.mesh(vec![primitive]))
+    }
+
+    /// Adds a node placed by `transform` relative to its parent and
+    /// returns its index.
+    pub(crate) fn node(
+        &mut self,
+        name: Option<&str>,
+        transform: &Transform,
+        mesh: Option<usize>,
+        children: Vec<usize>,
+    ) -> usize {
+        let mut node = json!({
+            "translation": transform.translation.to_array(),
+            "rotation": transform.rotation.to_array(),
+            "scale": transform.scale.to_array(),
+        });
+        if let Some(name) = name {
+            node["name"] = json!(name);
+        }
+        if let Some(mesh) = mesh {
+            node["mesh"] = json!(mesh);
+        }
+        if !children.is_empty() {
+            node["children"] = json!(children);
+        }
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    /// Writes the GLB with a single scene made of the node `root`.
+    pub(crate) fn write(&self, out: &mut impl Write, root: usize) -> io::Result<()> {
+        let mut document = Map::new();
+        document.insert(
+            "asset".into(),
+            json!({ "version": "2.0", "generator": "bevy_lsystems" }),
+        );
+        document.insert("scene".into(), json!(0));
+        document.insert("scenes".into(), json!([{ "nodes": [root] }]));
+        // glTF doesn't allow empty arrays.
+        for (key, values) in [
+            ("nodes", &self.nodes),
+            ("meshes", &self.meshes),
+            ("materials", &self.materials),
+            ("accessors", &self.accessors),
+            ("bufferViews", &self.buffer_views),
+        ] {
+            if !values.is_empty() {
+                document.insert(key.into(), json!(values));
+            }
+        }
+        if !self.buffer.is_empty() {
+            document.insert(
+                "buffers".into(),
+                json!([{ "byteLength": self.buffer.len() }]),
+            );
+        }
+        if self.unlit {
+            document.insert("extensionsUsed".into(), json!(["KHR_materials_unlit"]));
+        }
+
+        // Both chunks are padded to 4 bytes, JSON with spaces.
+        let mut json = serde_json::to_vec(&document)?;
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut buffer = self.buffer.clone();
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+        let bin_chunk_len = if buffer.is_empty() {
+            0
+        } else {
+            8 + buffer.len()
+        };
+        let total = 12 + 8 + json.len() + bin_chunk_len;
+
+        out.write_all(b"glTF")?;
+        out.write_all(&2u32.to_le_bytes())?;
+        out.write_all(&(total as u32).to_le_bytes())?;
+        out.write_all(&(json.len() as u32).to_le_bytes())?;
+        out.write_all(b"JSON")?;
+        out.write_all(&json)?;
+        if !buffer.is_empty() {
+            out.write_all(&(buffer.len() as u32).to_le_bytes())?;
+            out.write_all(b"BIN\0")?;
+            out.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+}
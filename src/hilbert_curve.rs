@@ -6,6 +6,7 @@ use crate::lsys_rendering::LineMaterial;
 use crate::lsystems::LSys;
 use crate::lsystems::LSysDrawer;
 use crate::lsystems::LSysRules;
+use crate::turtle::{Tropism, Turtle, TurtleState};
 
 use bevy::prelude::*;
 
@@ -40,7 +41,7 @@ impl HilbertCurve {
             color,
             turn_angle: PI / 2.0,
             lsys,
-            line_mesh: LineList { lines: vec![] },
+            line_mesh: LineList::default(),
             mesh_handle: Handle::<Mesh>::default(),
             material_handle: Handle::<LineMaterial>::default(),
             ..Default::default()
@@ -52,7 +53,7 @@ impl Default for HilbertCurve {
     fn default() -> Self {
         let mut tmp = Self {
             start_pos: Vec3::new(0.0, 0.0, 0.0),
-            turn_angle: 90.0,
+            turn_angle: PI / 2.0,
             start_heading: Quat::from_rotation_y(0.0),
             start_left: Quat::from_rotation_y(-PI / 2.0),
             segment_length: 0.1,
@@ -69,8 +70,11 @@ impl Default for HilbertCurve {
                     ],
                 ),
                 iterations: 2,
+                interpretation: Default::default(),
+                cache: Default::default(),
+                prediction: Default::default(),
             },
-            line_mesh: LineList { lines: vec![] },
+            line_mesh: LineList::default(),
             mesh_handle: Handle::<Mesh>::default(),
             material_handle: Handle::<LineMaterial>::default(),
         };
@@ -99,44 +103,33 @@ pub fn add_default_hilbert_curve(
 
 impl GenerateLineList for HilbertCurve {
     fn generate_line_list(&self) -> LineList {
-        let mut line_list = LineList { lines: vec![] };
-        let mut current_pos = self.start_pos;
-        let mut current_heading = self.start_heading;
-        let mut current_left = current_heading * Quat::from_rotation_y(-PI / 2.0);
-        let mut line_length = self.segment_length;
-
-        for c in self.lsys.rules.eval(&self.lsys.iterations).unwrap().chars() {
-            match c {
-                'A' | 'B' | 'C' | 'D' | 'F' => {
-                    let new_pos =
-                        current_pos + current_heading.mul_vec3(Vec3::new(0.0, 0.0, line_length));
-                    line_list.lines.push((current_pos, new_pos));
-                    current_pos = new_pos;
-                }
-
-                '+' => {
-                    current_heading = current_left.clone();
-                    current_left = current_left.inverse();
-                }
+        let turtle = Turtle {
+            start: TurtleState::from_rotation(self.start_pos, self.start_heading, 1.0),
+            step: self.segment_length,
+            angle: self.turn_angle,
+            max_segments: self.lsys.rules.limits.max_segments,
+            interpretation: self.lsys.interpretation.clone(),
+            tropism: Tropism::default(),
+            width_taper: 1.0,
+            palette: Vec::new(),
+            gradient: Default::default(),
+        };
 
-                '-' => {
-                    current_heading = current_left.inverse();
-                }
-                '&' => current_heading = current_heading,
-                '^' => {
-                    current_heading = current_heading * Quat::from_rotation_x(-PI / 2.0);
-                }
-                '|' => {
-                    current_heading = current_heading * Quat::from_rotation_y(PI);
-                }
-                '/' => {
-                    current_heading = current_heading * Quat::from_rotation_z(PI / 2.0);
-                }
-                _ => {}
+        match self
+            .lsys
+            .rules
+            .derive(&self.lsys.iterations)
+            .and_then(|symbols| {
+                turtle
+                    .interpret(&symbols, None)
+                    .expect("interpretation without a cancel flag always finishes")
+            }) {
+            Ok(line_list) => line_list,
+            Err(e) => {
+                error!("{}: {}", self.lsys.name, e);
+                LineList::default()
             }
         }
-
-        line_list
     }
 }
 
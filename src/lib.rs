@@ -0,0 +1,88 @@
+//! L-system plants for Bevy: grammars, the turtle that draws them, and the
+//! systems that keep a `FractalPlant`'s meshes up to date.
+
+use bevy::{asset::load_internal_asset, prelude::*};
+
+mod export;
+mod foliage;
+mod fractal_plant;
+mod glb;
+mod hilbert_curve;
+mod lifecycle;
+mod lsys_expr;
+mod lsys_rendering;
+mod lsystems;
+mod save_load;
+mod svg;
+mod tube_mesh;
+mod turtle;
+mod wind;
+
+pub use export::ExportFormat;
+pub use fractal_plant::{
+    FractalPlant, GeometrySnapshot, LineList, PlantSpawnPoint, SideMenuOptions,
+};
+pub use lifecycle::Simulation;
+pub use lsys_rendering::{FractalPlantUpdateEvent, LineMaterial};
+pub use lsystems::{
+    EvaluationLimits, LSys, LSysRules, LSystemEvaluationError, ParametricRule, RuleSource,
+    SymbolBuffer,
+};
+pub use svg::SvgProjection;
+pub use turtle::{Interpretation, TurtleCommand};
+pub use wind::Wind;
+
+/// Grows, ages and sways every `FractalPlant`. Spawn a `FractalPlant`, or a
+/// `PlantSpawnPoint` for a default one, to add a plant, and send a
+/// `FractalPlantUpdateEvent` after editing one.
+pub struct LSystemPlugin;
+
+impl Plugin for LSystemPlugin {
+    fn build(&self, app: &mut App) {
+        // The shaders ship inside the crate, so apps don't need copies of
+        // them in their asset folders.
+        load_internal_asset!(
+            app,
+            wind::WIND_SHADER_HANDLE,
+            "shaders/wind.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            lsys_rendering::LINE_MATERIAL_SHADER_HANDLE,
+            "shaders/line_material.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            wind::SWAYING_MATERIAL_SHADER_HANDLE,
+            "shaders/swaying_material.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_plugins((
+            MaterialPlugin::<LineMaterial>::default(),
+            MaterialPlugin::<wind::SwayingMaterial>::default(),
+        ))
+        .init_resource::<foliage::SurfaceMaterials>()
+        .init_resource::<Simulation>()
+        .init_resource::<Wind>()
+        .add_event::<FractalPlantUpdateEvent>()
+        .add_systems(Startup, foliage::setup_surface_materials)
+        .add_systems(
+            Update,
+            (
+                fractal_plant::add_new_fractal_plants,
+                fractal_plant::set_up_fractal_plants,
+                lifecycle::age_plants,
+                fractal_plant::update_plant_meshes,
+                fractal_plant::finish_plant_meshes,
+                fractal_plant::animate_plant_growth,
+                fractal_plant::update_plant_materials,
+            )
+                // New plants have to exist before their first updates are read.
+                .chain(),
+        )
+        .add_systems(Update, wind::blow_wind);
+    }
+}
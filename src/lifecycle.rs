@@ -0,0 +1,224 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{fractal_plant::FractalPlant, lsys_rendering::FractalPlantUpdateEvent};
+
+/// How much faster than real time the garden runs in time-lapse mode.
+const TIME_LAPSE_FACTOR: f32 = 60.0;
+
+/// Pace of simulated time for every plant in the garden.
+#[derive(Resource, Debug, Clone)]
+pub struct Simulation {
+    /// Simulated seconds per real second.
+    pub speed: f32,
+    pub paused: bool,
+    /// Runs the garden `TIME_LAPSE_FACTOR` times faster on top of `speed`.
+    pub time_lapse: bool,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            paused: false,
+            time_lapse: false,
+        }
+    }
+}
+
+impl Simulation {
+    /// Simulated seconds that pass during `delta` real seconds.
+    pub(crate) fn elapsed(&self, delta: f32) -> f32 {
+        match (self.paused, self.time_lapse) {
+            (true, _) => 0.0,
+            (false, false) => delta * self.speed,
+            (false, true) => delta * self.speed * TIME_LAPSE_FACTOR,
+        }
+    }
+}
+
+/// How a plant ages in the garden. Every `seconds_per_iteration` of
+/// simulated time it grows one more iteration of its L-system, until it
+/// reaches `mature_iterations`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Lifecycle {
+    /// Plants loaded from before the simulation existed stay as they are.
+    pub(crate) enabled: bool,
+    /// Simulated seconds since the plant was a seed.
+    pub(crate) age: f32,
+    pub(crate) seconds_per_iteration: f32,
+    /// The plant stops growing at this many iterations.
+    pub(crate) mature_iterations: usize,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            age: 0.0,
+            seconds_per_iteration: 30.0,
+            mature_iterations: 4,
+        }
+    }
+}
+
+impl Lifecycle {
+    /// Iterations of a plant this old.
+    pub(crate) fn iterations(&self) -> usize {
+        let grown = (self.age / self.seconds_per_iteration.max(0.01)) as usize;
+        grown.min(self.mature_iterations)
+    }
+
+    pub(crate) fn is_mature(&self) -> bool {
+        self.iterations() >= self.mature_iterations
+    }
+
+    /// Age at which a plant has grown `iterations`, for when the user sets
+    /// them by hand.
+    pub(crate) fn age_at(&self, iterations: usize) -> f32 {
+        iterations as f32 * self.seconds_per_iteration
+    }
+}
+
+/// Ages every living plant and rebuilds the ones that grew an iteration.
+pub fn age_plants(
+    time: Res<Time>,
+    simulation: Res<Simulation>,
+    mut query: Query<(Entity, &mut FractalPlant)>,
+    mut update_writer: EventWriter<FractalPlantUpdateEvent>,
+) {
+    let elapsed = simulation.elapsed(time.delta_seconds());
+    for (entity, mut plant) in query.iter_mut() {
+        if !plant.lifecycle.enabled || plant.lifecycle.is_mature() {
+            continue;
+        }
+        plant.lifecycle.age += elapsed;
+        let iterations = plant.lifecycle.iterations();
+        if plant.lsys.iterations != iterations {
+            plant.lsys.iterations = iterations;
+            update_writer.send(FractalPlantUpdateEvent::MESH(entity));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    fn growing(age: f32) -> Lifecycle {
+        Lifecycle {
+            enabled: true,
+            age,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn iterations_step_at_stage_boundaries() {
+        assert_eq!(growing(0.0).iterations(), 0);
+        assert_eq!(growing(29.9).iterations(), 0);
+        assert_eq!(growing(30.0).iterations(), 1);
+        assert_eq!(growing(119.9).iterations(), 3);
+        assert!(!growing(119.9).is_mature());
+        assert_eq!(growing(120.0).iterations(), 4);
+        assert!(growing(120.0).is_mature());
+        // Growth stops at the maturity limit.
+        assert_eq!(growing(1e6).iterations(), 4);
+        let lifecycle = growing(0.0);
+        assert_eq!(growing(lifecycle.age_at(3)).iterations(), 3);
+    }
+
+    #[test]
+    fn simulation_speed_scales_time() {
+        let simulation = Simulation {
+            speed: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(simulation.elapsed(0.5), 1.0);
+        let time_lapse = Simulation {
+            time_lapse: true,
+            ..simulation.clone()
+        };
+        assert_eq!(time_lapse.elapsed(0.5), TIME_LAPSE_FACTOR);
+        let paused = Simulation {
+            paused: true,
+            ..time_lapse
+        };
+        assert_eq!(paused.elapsed(0.5), 0.0);
+    }
+
+    /// Runs `age_plants` once, `seconds` after the last run, and returns the
+    /// plants that were sent a mesh update.
+    fn age(world: &mut World, seconds: f32) -> Vec<Entity> {
+        world
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs_f32(seconds));
+        world.run_system_once(age_plants);
+        world
+            .resource_mut::<Events<FractalPlantUpdateEvent>>()
+            .drain()
+            .filter_map(|update| match update {
+                FractalPlantUpdateEvent::MESH(entity) => Some(entity),
+                FractalPlantUpdateEvent::MATERIAL(_) => None,
+            })
+            .collect()
+    }
+
+    fn garden(lifecycle: Lifecycle) -> (World, Entity) {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        world.init_resource::<Simulation>();
+        world.init_resource::<Events<FractalPlantUpdateEvent>>();
+        let entity = world
+            .spawn(FractalPlant {
+                lifecycle,
+                ..Default::default()
+            })
+            .id();
+        let mut plant = world.get_mut::<FractalPlant>(entity).unwrap();
+        plant.lsys.iterations = lifecycle.iterations();
+        (world, entity)
+    }
+
+    #[test]
+    fn plants_are_rebuilt_when_they_grow_an_iteration() {
+        let (mut world, plant) = garden(growing(25.0));
+        assert!(age(&mut world, 4.0).is_empty());
+        assert_eq!(age(&mut world, 2.0), vec![plant]);
+        let plant = world.get::<FractalPlant>(plant).unwrap();
+        assert_eq!(plant.lsys.iterations, 1);
+        assert_eq!(plant.lifecycle.age, 31.0);
+    }
+
+    #[test]
+    fn mature_plants_stop_growing_and_stay() {
+        let (mut world, plant) = garden(growing(119.0));
+        assert_eq!(age(&mut world, 2.0), vec![plant]);
+        assert!(age(&mut world, 1000.0).is_empty());
+        // Plants don't die: a mature plant keeps its age and iterations.
+        let mature = world.get::<FractalPlant>(plant).unwrap();
+        assert_eq!(mature.lsys.iterations, 4);
+        assert_eq!(mature.lifecycle.age, 121.0);
+    }
+
+    #[test]
+    fn disabled_plants_keep_their_iterations() {
+        let mut still = growing(0.0);
+        still.enabled = false;
+        let (mut world, plant) = garden(still);
+        world
+            .get_mut::<FractalPlant>(plant)
+            .unwrap()
+            .lsys
+            .iterations = 6;
+        assert!(age(&mut world, 1000.0).is_empty());
+        let plant = world.get::<FractalPlant>(plant).unwrap();
+        assert_eq!(plant.lsys.iterations, 6);
+        assert_eq!(plant.lifecycle.age, 0.0);
+    }
+}
@@ -5,10 +5,9 @@ use bevy::{
 };
 use bevy_egui::{egui, EguiContext, EguiContexts, EguiPlugin, EguiSet, EguiUserTextures};
 
+use bevy_lsystems::{FractalPlant, FractalPlantUpdateEvent, SideMenuOptions, Simulation, Wind};
+
 use crate::{
-    fractal_plant::FractalPlant,
-    lsys_rendering::FractalPlantUpdateEvent,
-    lsystems::LSysDrawer,
     pickup::{ActiveEntityCandidate, Holder},
     player::ActiveEntity,
 };
@@ -37,22 +36,40 @@ pub struct PanelOccupiedScreenSpace {
     pub bottom: f32,
 }
 
-pub trait SideMenuOptions {
-    fn side_menu_options(&mut self, ui: &mut egui::Ui, id: Entity, commands: &mut Commands);
-}
-
 pub fn test_side_and_top_panel(
     mut contexts: EguiContexts,
     mut occupied_space: ResMut<PanelOccupiedScreenSpace>,
+    mut simulation: ResMut<Simulation>,
+    mut wind: ResMut<Wind>,
     mut query: Query<(Entity, &mut FractalPlant)>,
     mut active_candidate_query: Query<(Entity, &mut ActiveEntityCandidate)>,
     active_entity: ResMut<ActiveEntity>,
+    mut update_writer: EventWriter<FractalPlantUpdateEvent>,
     mut commands: Commands,
 ) {
     occupied_space.top = egui::TopBottomPanel::top("top_panel")
         .resizable(true)
         .show(contexts.ctx_mut(), |ui| {
-            ui.label("Top Panel");
+            ui.horizontal(|ui| {
+                ui.label("Garden time");
+                let label = if simulation.paused { "Resume" } else { "Pause" };
+                if ui.button(label).clicked() {
+                    simulation.paused = !simulation.paused;
+                }
+                ui.add(egui::Slider::new(&mut simulation.speed, 0.0..=10.0).text("speed"));
+                ui.checkbox(&mut simulation.time_lapse, "Time-lapse");
+                ui.separator();
+                ui.label("Wind");
+                ui.add(egui::Slider::new(&mut wind.strength, 0.0..=0.5).text("strength"));
+                let mut heading = wind.direction.z.atan2(wind.direction.x).to_degrees();
+                if ui
+                    .add(egui::Slider::new(&mut heading, -180.0..=180.0).text("direction"))
+                    .changed()
+                {
+                    let heading = heading.to_radians();
+                    wind.direction = Vec3::new(heading.cos(), 0.0, heading.sin());
+                }
+            });
         })
         .response
         .rect
@@ -65,7 +82,19 @@ pub fn test_side_and_top_panel(
 
             for (entity, mut tree) in query.iter_mut() {
                 match active_entity.id == Some(entity) {
-                    true => tree.side_menu_options(ui, entity, &mut commands),
+                    true => {
+                        // Only rebuild what the edit touched, a color tweak
+                        // shouldn't restart the mesh task.
+                        let geometry = tree.geometry_snapshot();
+                        let appearance = tree.appearance();
+                        tree.side_menu_options(ui, entity, &mut commands);
+                        if tree.geometry_snapshot() != geometry {
+                            update_writer.send(FractalPlantUpdateEvent::MESH(entity));
+                        }
+                        if tree.appearance() != appearance {
+                            update_writer.send(FractalPlantUpdateEvent::MATERIAL(entity));
+                        }
+                    }
                     false => {}
                 }
             }
@@ -0,0 +1,334 @@
+/// Arithmetic and boolean expressions used by parametric L-system rules,
+/// e.g. the `t * 0.9` in `A(t) -> F(t * 0.9)` or the condition `t > 2`.
+///
+/// Booleans are plain numbers: comparisons give `1.0` or `0.0` and anything
+/// non-zero counts as true.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expr {
+    Num(f32),
+    /// Index into the formal parameters of the predecessor.
+    Var(usize),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExprError {
+    UnknownParameter(String),
+    Syntax(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+impl Expr {
+    /// Parses `src`, resolving identifiers against the names in `formals`.
+    pub(crate) fn parse(src: &str, formals: &[String]) -> Result<Expr, ExprError> {
+        let mut parser = ExprParser {
+            chars: src.chars().collect(),
+            pos: 0,
+            formals,
+        };
+        let expr = parser.parse_or()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(ExprError::Syntax(format!(
+                "unexpected '{}' in expression \"{}\"",
+                parser.chars[parser.pos], src
+            )));
+        }
+        Ok(expr)
+    }
+
+    pub(crate) fn eval(&self, args: &[f32]) -> f32 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Var(i) => args.get(*i).copied().unwrap_or(0.0),
+            Expr::Neg(e) => -e.eval(args),
+            Expr::Not(e) => bool_to_f32(e.eval(args) == 0.0),
+            Expr::Bin(op, a, b) => {
+                let a = a.eval(args);
+                let b = b.eval(args);
+                match op {
+                    BinOp::Add => a + b,
+                    BinOp::Sub => a - b,
+                    BinOp::Mul => a * b,
+                    BinOp::Div => a / b,
+                    BinOp::Pow => a.powf(b),
+                    BinOp::Lt => bool_to_f32(a < b),
+                    BinOp::Gt => bool_to_f32(a > b),
+                    BinOp::Le => bool_to_f32(a <= b),
+                    BinOp::Ge => bool_to_f32(a >= b),
+                    BinOp::Eq => bool_to_f32(a == b),
+                    BinOp::Ne => bool_to_f32(a != b),
+                    BinOp::And => bool_to_f32(a != 0.0 && b != 0.0),
+                    BinOp::Or => bool_to_f32(a != 0.0 || b != 0.0),
+                }
+            }
+        }
+    }
+}
+
+fn bool_to_f32(b: bool) -> f32 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Recursive descent parser, one method per precedence level from loosest
+/// (`||`) to tightest (unary operators and atoms).
+struct ExprParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    formals: &'a [String],
+}
+
+impl<'a> ExprParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Consumes `token` if the input continues with it.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let matches = token
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+        if matches {
+            self.pos += token.chars().count();
+        }
+        matches
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.parse_and()?;
+        while self.eat("||") {
+            let rhs = self.parse_and()?;
+            lhs = Expr::Bin(BinOp::Or, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.parse_comparison()?;
+        while self.eat("&&") {
+            let rhs = self.parse_comparison()?;
+            lhs = Expr::Bin(BinOp::And, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ExprError> {
+        let lhs = self.parse_sum()?;
+        // Two character operators have to be tried before their prefixes.
+        let op = if self.eat("<=") {
+            BinOp::Le
+        } else if self.eat(">=") {
+            BinOp::Ge
+        } else if self.eat("==") {
+            BinOp::Eq
+        } else if self.eat("!=") {
+            BinOp::Ne
+        } else if self.eat("<") {
+            BinOp::Lt
+        } else if self.eat(">") {
+            BinOp::Gt
+        } else if self.eat("=") {
+            BinOp::Eq
+        } else {
+            return Ok(lhs);
+        };
+        let rhs = self.parse_sum()?;
+        Ok(Expr::Bin(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.parse_product()?;
+        loop {
+            let op = if self.eat("+") {
+                BinOp::Add
+            } else if self.eat("-") {
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.parse_product()?;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = if self.eat("*") {
+                BinOp::Mul
+            } else if self.eat("/") {
+                BinOp::Div
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.parse_unary()?;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ExprError> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.parse_unary()?)));
+        }
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_power()
+    }
+
+    fn parse_power(&mut self) -> Result<Expr, ExprError> {
+        let base = self.parse_atom()?;
+        if self.eat("^") {
+            // Right associative, so 2^3^2 == 2^(3^2).
+            let exponent = self.parse_unary()?;
+            return Ok(Expr::Bin(BinOp::Pow, Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr, ExprError> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.parse_or()?;
+                if !self.eat(")") {
+                    return Err(ExprError::Syntax("missing ')' in expression".to_string()));
+                }
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self.pos < self.chars.len()
+                    && (self.chars[self.pos].is_ascii_digit() || self.chars[self.pos] == '.')
+                {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                text.parse::<f32>()
+                    .map(Expr::Num)
+                    .map_err(|_| ExprError::Syntax(format!("invalid number \"{}\"", text)))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let start = self.pos;
+                while self.pos < self.chars.len()
+                    && (self.chars[self.pos].is_alphanumeric() || self.chars[self.pos] == '_')
+                {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                self.formals
+                    .iter()
+                    .position(|f| *f == name)
+                    .map(Expr::Var)
+                    .ok_or(ExprError::UnknownParameter(name))
+            }
+            Some(c) => Err(ExprError::Syntax(format!(
+                "unexpected '{}' in expression",
+                c
+            ))),
+            None => Err(ExprError::Syntax(
+                "unexpected end of expression".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str, formals: &[&str], args: &[f32]) -> f32 {
+        let formals: Vec<String> = formals.iter().map(|f| f.to_string()).collect();
+        Expr::parse(src, &formals).unwrap().eval(args)
+    }
+
+    #[test]
+    fn arithmetic_precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[], &[]), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", &[], &[]), 9.0);
+        assert_eq!(eval("8 - 4 - 2", &[], &[]), 2.0);
+        assert_eq!(eval("8 / 4 / 2", &[], &[]), 1.0);
+        assert_eq!(eval("2 ^ 3 ^ 2", &[], &[]), 512.0);
+        assert_eq!(eval("-2 ^ 2", &[], &[]), -4.0);
+        assert_eq!(eval("2 * -3", &[], &[]), -6.0);
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        assert_eq!(eval("1 + 1 == 2", &[], &[]), 1.0);
+        assert_eq!(eval("t > 2 && t <= 3", &["t"], &[3.0]), 1.0);
+        assert_eq!(eval("t > 2 && t <= 3", &["t"], &[4.0]), 0.0);
+        assert_eq!(eval("t < 1 || t >= 4", &["t"], &[4.0]), 1.0);
+        // && binds tighter than ||.
+        assert_eq!(eval("1 || 0 && 0", &[], &[]), 1.0);
+        assert_eq!(eval("!(t != 2)", &["t"], &[2.0]), 1.0);
+        assert_eq!(eval("t = 2", &["t"], &[2.0]), 1.0);
+    }
+
+    #[test]
+    fn parameters_by_position() {
+        assert_eq!(eval("l * w", &["l", "w"], &[2.0, 0.5]), 1.0);
+        assert_eq!(eval("w - l", &["l", "w"], &[2.0, 0.5]), -1.5);
+        assert_eq!(eval("long_name", &["long_name"], &[7.0]), 7.0);
+    }
+
+    #[test]
+    fn errors() {
+        let formals = vec!["t".to_string()];
+        assert_eq!(
+            Expr::parse("t * x", &formals),
+            Err(ExprError::UnknownParameter("x".to_string()))
+        );
+        assert!(matches!(
+            Expr::parse("(t + 1", &formals),
+            Err(ExprError::Syntax(_))
+        ));
+        assert!(matches!(
+            Expr::parse("t +", &formals),
+            Err(ExprError::Syntax(_))
+        ));
+        assert!(matches!(
+            Expr::parse("t 1", &formals),
+            Err(ExprError::Syntax(_))
+        ));
+        assert!(matches!(
+            Expr::parse("1..2", &formals),
+            Err(ExprError::Syntax(_))
+        ));
+        assert!(matches!(
+            Expr::parse("t # 2", &formals),
+            Err(ExprError::Syntax(_))
+        ));
+    }
+}
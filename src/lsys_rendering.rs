@@ -6,10 +6,10 @@ use bevy::{
     reflect::TypePath,
     render::{
         color::Color,
-        mesh::MeshVertexBufferLayout,
+        mesh::{MeshVertexAttribute, MeshVertexBufferLayout},
         render_resource::{
             AsBindGroup, PolygonMode, RenderPipelineDescriptor, ShaderRef,
-            SpecializedMeshPipelineError,
+            SpecializedMeshPipelineError, VertexFormat,
         },
     },
 };
@@ -17,6 +17,32 @@ use serde::{Deserialize, Serialize};
 
 use crate::fractal_plant::FractalPlant;
 use crate::fractal_plant::LineList;
+use crate::wind::WindUniform;
+
+/// Where each vertex of a plant's branch mesh grows from, and how many
+/// generations before the plant's last one its segment is born, so that the
+/// vertex stage can show the plant partly grown.
+pub(crate) const ATTRIBUTE_GROWTH: MeshVertexAttribute =
+    MeshVertexAttribute::new("PlantGrowth", 271_828_182, VertexFormat::Float32x4);
+
+/// `line_material.wgsl`, built into the crate by `LSystemPlugin`.
+pub(crate) const LINE_MATERIAL_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(123543771962033346461015879066949718360);
+
+/// Feeds `ATTRIBUTE_GROWTH` to the vertex stage of meshes that carry it.
+pub(crate) fn specialize_growth(
+    descriptor: &mut RenderPipelineDescriptor,
+    layout: &MeshVertexBufferLayout,
+) -> Result<(), SpecializedMeshPipelineError> {
+    if layout.contains(ATTRIBUTE_GROWTH) {
+        let growth = layout.get_layout(&[ATTRIBUTE_GROWTH.at_shader_location(8)])?;
+        descriptor.vertex.buffers[0]
+            .attributes
+            .extend(growth.attributes);
+        descriptor.vertex.shader_defs.push("PLANT_GROWTH".into());
+    }
+    Ok(())
+}
 
 pub trait GenerateLineList {
     fn generate_line_list(&self) -> LineList;
@@ -32,6 +58,15 @@ pub enum FractalPlantUpdateEvent {
 pub struct LineMaterial {
     #[uniform(0)]
     color: Color,
+    /// Set by `blow_wind` whenever the wind changes.
+    #[uniform(1)]
+    #[serde(skip)]
+    pub(crate) wind: WindUniform,
+    /// Generations the plant still has to grow, set by
+    /// `animate_plant_growth`.
+    #[uniform(2)]
+    #[serde(skip)]
+    pub(crate) growth_left: f32,
 }
 #[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct LineMesh {
@@ -45,7 +80,7 @@ pub struct LineMesh {
 impl Default for LineMesh {
     fn default() -> Self {
         Self {
-            line_list: LineList { lines: vec![] },
+            line_list: LineList::default(),
             mesh_handle: Handle::<Mesh>::default(),
             material_handle: Handle::<LineMaterial>::default(),
         }
@@ -54,23 +89,44 @@ impl Default for LineMesh {
 
 impl LineMaterial {
     pub fn new(color: Color) -> Self {
-        Self { color }
+        Self {
+            color,
+            wind: WindUniform::default(),
+            growth_left: 0.0,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
     }
 }
 
 impl Material for LineMaterial {
+    fn vertex_shader() -> ShaderRef {
+        LINE_MATERIAL_SHADER_HANDLE.into()
+    }
+
     fn fragment_shader() -> ShaderRef {
-        "shaders/line_material.wgsl".into()
+        LINE_MATERIAL_SHADER_HANDLE.into()
+    }
+
+    /// Shadows of the lines sway and grow with them.
+    fn prepass_vertex_shader() -> ShaderRef {
+        LINE_MATERIAL_SHADER_HANDLE.into()
     }
 
     fn specialize(
         _pipeline: &MaterialPipeline<Self>,
         descriptor: &mut RenderPipelineDescriptor,
-        _layout: &MeshVertexBufferLayout,
+        layout: &MeshVertexBufferLayout,
         _key: MaterialPipelineKey<Self>,
     ) -> Result<(), SpecializedMeshPipelineError> {
         // This is the important part to tell bevy to render this material as a line between vertices
         descriptor.primitive.polygon_mode = PolygonMode::Line;
-        Ok(())
+        specialize_growth(descriptor, layout)
     }
 }
@@ -1,24 +1,89 @@
+use std::collections::HashMap;
 use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bevy::prelude::*;
 use bevy::render::color::Color;
 use bevy::render::mesh::MeshVertexAttribute;
 use bevy::render::mesh::PrimitiveTopology;
 use bevy::render::render_asset::RenderAssetUsages;
-use lsystem::LSystem;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
 use serde::{Deserialize, Serialize};
 
-use lsystem::MapRules;
-
 use crate::fractal_plant::FractalPlant;
+use crate::lsys_expr::{Expr, ExprError};
 use crate::lsys_rendering::GenerateLineList;
+use crate::turtle::Interpretation;
 
 #[derive(Component, Debug, Serialize, Deserialize)]
-pub(crate) struct LSys {
-    pub(crate) name: String,
-    pub(crate) rules: LSysRules,
-    pub(crate) iterations: usize,
+pub struct LSys {
+    pub name: String,
+    pub rules: LSysRules,
+    pub iterations: usize,
+    /// How the turtle draws the derived symbols.
+    #[serde(default)]
+    pub interpretation: Interpretation,
+    /// Shared with the mesh task currently deriving this system.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) cache: Arc<Mutex<DerivationCache>>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) prediction: PredictionCache,
+}
+
+impl LSys {
+    pub fn new(name: String, rules: LSysRules, iterations: usize) -> Self {
+        Self {
+            name,
+            rules,
+            iterations,
+            interpretation: Default::default(),
+            cache: Default::default(),
+            prediction: Default::default(),
+        }
+    }
+}
+
+/// The result of `LSysRules::predict_lengths` for the rules and iteration
+/// count it was last asked about, so that showing it every frame doesn't
+/// recompile the rules every frame.
+#[derive(Debug, Default)]
+pub(crate) struct PredictionCache {
+    key: Option<(LSysRules, usize)>,
+    /// `None` when the rules don't compile.
+    lengths: Option<Vec<f64>>,
+}
+
+impl PredictionCache {
+    pub(crate) fn predict(&mut self, rules: &LSysRules, levels: usize) -> Option<&[f64]> {
+        let stale = match &self.key {
+            Some((key_rules, key_levels)) => key_rules != rules || *key_levels != levels,
+            None => true,
+        };
+        if stale {
+            self.lengths = rules.predict_lengths(&levels).ok();
+            self.key = Some((rules.clone(), levels));
+        }
+        self.lengths.as_deref()
+    }
+}
+
+/// Every derivation step of an `LSys` so far, valid as long as the rules and
+/// axiom they were derived from don't change. Raising the iteration count
+/// only rewrites the missing steps, lowering it or changing anything else
+/// about the plant costs nothing.
+#[derive(Default)]
+pub(crate) struct DerivationCache {
+    key: Option<LSysRules>,
+    rules: Option<ProductionRules>,
+    /// `steps[0]` is the axiom, `steps[i]` the result of `i` rewrites.
+    steps: Vec<SymbolBuffer>,
+    /// No rule applies to the last step anymore.
+    finished: bool,
+    /// The rewrite after the last step failed, so don't retry it.
+    error: Option<LSystemEvaluationError>,
 }
 
 #[derive(Component, Debug, Serialize, Deserialize)]
@@ -26,40 +91,809 @@ pub(crate) struct LSysDrawer {
     pub(crate) changed: bool,
 }
 
-#[derive(Component, Debug, Serialize, Deserialize)]
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize)]
+
+pub struct LSysRules {
+    pub axiom: Vec<char>,
+    pub rules: Vec<(char, String)>,
+    /// Predecessors with several weighted successors, one of which is picked
+    /// at random every time the predecessor is rewritten.
+    #[serde(default)]
+    pub stochastic_rules: Vec<(char, Vec<(f32, String)>)>,
+    /// Seed for picking stochastic successors, so the same seed always grows
+    /// the same plant.
+    #[serde(default)]
+    pub seed: u64,
+    /// Rules on parametric modules such as `A(t) : t > 2 -> F(t * 0.9)[+A(t - 1)]`,
+    /// optionally context-sensitive as in `B(x) < A(t) > C -> ...`.
+    #[serde(default)]
+    pub parametric_rules: Vec<ParametricRule>,
+    /// Symbols skipped when matching the context of a rule, typically the
+    /// turtle rotations such as `+-<>`.
+    #[serde(default)]
+    pub ignore: Vec<char>,
+    #[serde(default)]
+    pub limits: EvaluationLimits,
+}
+
+/// Budgets that make an evaluation fail instead of exhausting memory or
+/// freezing the app on a rule that grows too fast.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EvaluationLimits {
+    /// Maximum number of modules after any rewrite.
+    pub max_symbols: usize,
+    /// Maximum number of line segments the turtle may draw.
+    pub max_segments: usize,
+}
+
+/// A parametric production, kept as the text the user typed so that it can
+/// be edited in the side panel and saved as is.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParametricRule {
+    /// Modules that have to precede the predecessor, e.g. the `A` in
+    /// `A < B > C`. Empty means any.
+    #[serde(default)]
+    pub left_context: String,
+    /// Symbol and formal parameter names, e.g. `A(t)` or `F(l,w)`.
+    pub predecessor: String,
+    /// Modules that have to follow the predecessor. May descend into
+    /// branches, e.g. `C[D]E`. Empty means any.
+    #[serde(default)]
+    pub right_context: String,
+    /// Expression over the formal parameters of the contexts and the
+    /// predecessor; the rule only applies when it is non-zero. Empty means
+    /// always.
+    #[serde(default)]
+    pub condition: String,
+    /// Modules whose parameters are expressions over the formal parameters.
+    pub successor: String,
+}
+
+/// Derivation state as flat arrays instead of one allocation per module.
+/// The parameters of module `i` are `params[param_start[i]..param_start[i + 1]]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolBuffer {
+    symbols: Vec<char>,
+    param_start: Vec<u32>,
+    params: Vec<f32>,
+}
+
+/// Which part of an `LSysRules` an error comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleSource {
+    Axiom,
+    Rule(usize),
+    StochasticRule(usize),
+    ParametricRule(usize),
+}
+
+/// A parametric rule after its expressions have been parsed. Context
+/// patterns keep only symbol and arity, their formal parameters are bound in
+/// order left context, predecessor, right context.
+struct CompiledParametricRule {
+    left_context: Vec<(char, usize)>,
+    symbol: char,
+    arity: usize,
+    right_context: Vec<(char, usize)>,
+    condition: Option<Expr>,
+    successor: Vec<(char, Vec<Expr>)>,
+}
+
+/// All productions of an `LSysRules`, ready to be applied. Parametric rules
+/// are tried first, in order; otherwise every predecessor maps to a list of
+/// weighted successors. Deterministic rules are stored as a single successor.
+pub(crate) struct ProductionRules {
+    parametric: Vec<CompiledParametricRule>,
+    ignore: Vec<char>,
+    productions: HashMap<char, Vec<(f32, SymbolBuffer)>>,
+    rng: ChaCha8Rng,
+    max_symbols: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LSystemEvaluationError {
+    /// A character that can't start a module, like a stray `)` or `,`.
+    UnknownSymbol {
+        symbol: char,
+        source: RuleSource,
+    },
+    /// An expression refers to a name that isn't a formal parameter.
+    UnknownParameter {
+        name: String,
+        source: RuleSource,
+    },
+    InvalidRule {
+        source: RuleSource,
+        reason: String,
+    },
+    SizeLimitExceeded {
+        iteration: usize,
+        limit: usize,
+    },
+    SegmentLimitExceeded {
+        limit: usize,
+    },
+}
 
-pub(crate) struct LSysRules {
-    pub(crate) axiom: Vec<char>,
-    pub(crate) rules: Vec<(char, String)>,
+impl std::fmt::Display for RuleSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleSource::Axiom => write!(f, "axiom"),
+            RuleSource::Rule(i) => write!(f, "rule {}", i + 1),
+            RuleSource::StochasticRule(i) => write!(f, "stochastic rule {}", i + 1),
+            RuleSource::ParametricRule(i) => write!(f, "parametric rule {}", i + 1),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub(crate) enum LSystemEvaluationError {
-    EvaluationError,
+impl std::fmt::Display for LSystemEvaluationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LSystemEvaluationError::UnknownSymbol { symbol, source } => {
+                write!(f, "unknown symbol '{}' in {}", symbol, source)
+            }
+            LSystemEvaluationError::UnknownParameter { name, source } => {
+                write!(f, "unknown parameter \"{}\" in {}", name, source)
+            }
+            LSystemEvaluationError::InvalidRule { source, reason } => {
+                write!(f, "invalid {}: {}", source, reason)
+            }
+            LSystemEvaluationError::SizeLimitExceeded { iteration, limit } => {
+                write!(f, "more than {} symbols at iteration {}", limit, iteration)
+            }
+            LSystemEvaluationError::SegmentLimitExceeded { limit } => {
+                write!(f, "more than {} segments", limit)
+            }
+        }
+    }
+}
+
+impl LSystemEvaluationError {
+    fn from_expr(e: ExprError, source: RuleSource) -> Self {
+        match e {
+            ExprError::UnknownParameter(name) => {
+                LSystemEvaluationError::UnknownParameter { name, source }
+            }
+            ExprError::Syntax(reason) => LSystemEvaluationError::InvalidRule { source, reason },
+        }
+    }
+}
+
+impl Default for EvaluationLimits {
+    fn default() -> Self {
+        Self {
+            max_symbols: 1_000_000,
+            max_segments: 250_000,
+        }
+    }
+}
+
+impl SymbolBuffer {
+    pub(crate) fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        let mut param_start = Vec::with_capacity(capacity + 1);
+        param_start.push(0);
+        Self {
+            symbols: Vec::with_capacity(capacity),
+            param_start,
+            params: Vec::new(),
+        }
+    }
+
+    /// Number of modules.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.symbols.clear();
+        self.param_start.truncate(1);
+        self.params.clear();
+    }
+
+    /// Symbol of module `i`.
+    pub fn symbol(&self, i: usize) -> char {
+        self.symbols[i]
+    }
+
+    /// Parameters of module `i`, empty for plain symbols.
+    pub fn params(&self, i: usize) -> &[f32] {
+        &self.params[self.param_start[i] as usize..self.param_start[i + 1] as usize]
+    }
+
+    pub(crate) fn push(&mut self, symbol: char, params: impl IntoIterator<Item = f32>) {
+        self.symbols.push(symbol);
+        self.params.extend(params);
+        self.param_start.push(self.params.len() as u32);
+    }
+
+    pub(crate) fn extend_from(&mut self, other: &SymbolBuffer) {
+        for i in 0..other.len() {
+            self.push(other.symbol(i), other.params(i).iter().copied());
+        }
+    }
+
+    /// Every module with its parameters, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (char, &[f32])> + '_ {
+        (0..self.len()).map(move |i| (self.symbol(i), self.params(i)))
+    }
+}
+
+impl Default for SymbolBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for SymbolBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (symbol, params) in self.iter() {
+            write!(f, "{}", symbol)?;
+            if !params.is_empty() {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "({})", params.join(","))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits a module string into symbols and the raw text of their argument
+/// lists, e.g. `F(l,w)[+A]` into `F ["l", "w"]`, `[`, `+`, `A`, `]`.
+fn split_modules(
+    s: &str,
+    source: RuleSource,
+) -> Result<Vec<(char, Vec<String>)>, LSystemEvaluationError> {
+    let mut out = Vec::new();
+    let mut chars = s.chars().filter(|c| !c.is_whitespace()).peekable();
+    while let Some(symbol) = chars.next() {
+        if symbol == '(' || symbol == ')' || symbol == ',' {
+            return Err(LSystemEvaluationError::UnknownSymbol { symbol, source });
+        }
+        let mut args = Vec::new();
+        if chars.peek() == Some(&'(') {
+            chars.next();
+            let mut depth = 0;
+            let mut current = String::new();
+            loop {
+                match chars.next() {
+                    Some('(') => {
+                        depth += 1;
+                        current.push('(');
+                    }
+                    Some(')') if depth == 0 => break,
+                    Some(')') => {
+                        depth -= 1;
+                        current.push(')');
+                    }
+                    Some(',') if depth == 0 => args.push(std::mem::take(&mut current)),
+                    Some(c) => current.push(c),
+                    None => {
+                        return Err(LSystemEvaluationError::InvalidRule {
+                            source,
+                            reason: format!("missing ')' in \"{}\"", s),
+                        })
+                    }
+                }
+            }
+            args.push(current);
+        }
+        out.push((symbol, args));
+    }
+    Ok(out)
+}
+
+/// Parses a string of modules whose arguments are constant expressions,
+/// such as an axiom like `A(1)B(2,0.5)`.
+pub(crate) fn parse_modules(
+    s: &str,
+    source: RuleSource,
+) -> Result<SymbolBuffer, LSystemEvaluationError> {
+    let modules = split_modules(s, source)?;
+    let mut buffer = SymbolBuffer::with_capacity(modules.len());
+    for (symbol, args) in modules {
+        let params = args
+            .iter()
+            .map(|arg| Expr::parse(arg, &[]).map(|e| e.eval(&[])))
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| LSystemEvaluationError::from_expr(e, source))?;
+        buffer.push(symbol, params);
+    }
+    Ok(buffer)
+}
+
+impl ParametricRule {
+    fn compile(
+        &self,
+        source: RuleSource,
+    ) -> Result<CompiledParametricRule, LSystemEvaluationError> {
+        let left_context = split_modules(&self.left_context, source)?;
+        let predecessor = split_modules(&self.predecessor, source)?;
+        let right_context = split_modules(&self.right_context, source)?;
+        let [(symbol, predecessor_formals)] = predecessor.as_slice() else {
+            return Err(LSystemEvaluationError::InvalidRule {
+                source,
+                reason: format!(
+                    "predecessor \"{}\" must be a single module",
+                    self.predecessor
+                ),
+            });
+        };
+        let formals: Vec<String> = left_context
+            .iter()
+            .flat_map(|(_, f)| f.iter())
+            .chain(predecessor_formals.iter())
+            .chain(right_context.iter().flat_map(|(_, f)| f.iter()))
+            .cloned()
+            .collect();
+        let condition = match self.condition.trim() {
+            "" => None,
+            condition => Some(
+                Expr::parse(condition, &formals)
+                    .map_err(|e| LSystemEvaluationError::from_expr(e, source))?,
+            ),
+        };
+        let successor = split_modules(&self.successor, source)?
+            .into_iter()
+            .map(|(symbol, args)| {
+                let args = args
+                    .iter()
+                    .map(|arg| Expr::parse(arg, &formals))
+                    .collect::<Result<Vec<Expr>, _>>()
+                    .map_err(|e| LSystemEvaluationError::from_expr(e, source))?;
+                Ok((symbol, args))
+            })
+            .collect::<Result<Vec<_>, LSystemEvaluationError>>()?;
+        Ok(CompiledParametricRule {
+            left_context: left_context.iter().map(|(s, f)| (*s, f.len())).collect(),
+            symbol: *symbol,
+            arity: predecessor_formals.len(),
+            right_context: right_context.iter().map(|(s, f)| (*s, f.len())).collect(),
+            condition,
+            successor,
+        })
+    }
 }
 
 impl LSysRules {
     pub fn new(axiom: Vec<char>, rules: Vec<(char, String)>) -> Self {
-        Self { axiom, rules }
+        Self {
+            axiom,
+            rules,
+            stochastic_rules: Vec::new(),
+            seed: 0,
+            parametric_rules: Vec::new(),
+            ignore: Vec::new(),
+            limits: EvaluationLimits::default(),
+        }
     }
 
-    pub(crate) fn as_map_rules(&self) -> MapRules<char> {
-        let mut map_rules = MapRules::new();
-        for (k, v) in &self.rules {
-            map_rules.set_str(k.clone(), v.clone().as_str());
+    pub(crate) fn as_production_rules(&self) -> Result<ProductionRules, LSystemEvaluationError> {
+        let mut productions = HashMap::new();
+        for (i, (k, v)) in self.rules.iter().enumerate() {
+            productions.insert(*k, vec![(1.0, parse_modules(v, RuleSource::Rule(i))?)]);
+        }
+        for (i, (k, successors)) in self.stochastic_rules.iter().enumerate() {
+            productions.insert(
+                *k,
+                successors
+                    .iter()
+                    .map(|(weight, v)| {
+                        Ok((*weight, parse_modules(v, RuleSource::StochasticRule(i))?))
+                    })
+                    .collect::<Result<Vec<_>, LSystemEvaluationError>>()?,
+            );
+        }
+        let parametric = self
+            .parametric_rules
+            .iter()
+            .enumerate()
+            // Rows just added in the editor don't have a predecessor yet.
+            .filter(|(_, rule)| !rule.predecessor.trim().is_empty())
+            .map(|(i, rule)| rule.compile(RuleSource::ParametricRule(i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ProductionRules {
+            parametric,
+            ignore: self.ignore.clone(),
+            productions,
+            rng: ChaCha8Rng::seed_from_u64(self.seed),
+            max_symbols: self.limits.max_symbols,
+        })
+    }
+
+    /// Whether `predict_lengths` is exact. Conditions of parametric rules and
+    /// random picks of stochastic rules make it an estimate.
+    pub(crate) fn has_exact_prediction(&self) -> bool {
+        self.parametric_rules.is_empty()
+            && self
+                .stochastic_rules
+                .iter()
+                .all(|(_, successors)| successors.len() <= 1)
+    }
+
+    /// Predicts the number of modules after each rewrite, starting with the
+    /// axiom, from the growth matrix of the rules: entry `(a, b)` counts how
+    /// many `b` one `a` is rewritten into. Stochastic successors contribute
+    /// by weight, and a symbol with several parametric rules is assumed to
+    /// take the one that grows the most.
+    pub(crate) fn predict_lengths(
+        &self,
+        levels: &usize,
+    ) -> Result<Vec<f64>, LSystemEvaluationError> {
+        let rules = self.as_production_rules()?;
+        let axiom = parse_modules(&self.axiom.iter().collect::<String>(), RuleSource::Axiom)?;
+
+        let mut alphabet: Vec<char> = axiom.iter().map(|(symbol, _)| symbol).collect();
+        for successors in rules.productions.values() {
+            for (_, successor) in successors {
+                alphabet.extend(successor.iter().map(|(symbol, _)| symbol));
+            }
+        }
+        for rule in &rules.parametric {
+            alphabet.extend(rule.successor.iter().map(|(symbol, _)| *symbol));
+        }
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        let index = |c: char| alphabet.binary_search(&c).unwrap_or(0);
+
+        let n = alphabet.len();
+        let mut growth = vec![vec![0.0f64; n]; n];
+        for (a, row) in growth.iter_mut().enumerate() {
+            let symbol = alphabet[a];
+            let mut rewritten = false;
+            for rule in rules.parametric.iter().filter(|r| r.symbol == symbol) {
+                let mut counts = vec![0.0f64; n];
+                for (b, _) in &rule.successor {
+                    counts[index(*b)] += 1.0;
+                }
+                if counts.iter().sum::<f64>() > row.iter().sum::<f64>() {
+                    *row = counts;
+                }
+                rewritten = true;
+            }
+            if let Some(successors) = rules.productions.get(&symbol) {
+                let total: f64 = successors.iter().map(|(w, _)| w.max(0.0) as f64).sum();
+                let mut counts = vec![0.0f64; n];
+                for (weight, successor) in successors {
+                    let share = if successors.len() == 1 || total <= 0.0 {
+                        1.0 / successors.len() as f64
+                    } else {
+                        weight.max(0.0) as f64 / total
+                    };
+                    for (b, _) in successor.iter() {
+                        counts[index(b)] += share;
+                    }
+                }
+                if !rewritten || counts.iter().sum::<f64>() > row.iter().sum::<f64>() {
+                    *row = counts;
+                }
+                rewritten = true;
+            }
+            if !rewritten {
+                row[a] = 1.0;
+            }
+        }
+
+        let mut counts = vec![0.0f64; n];
+        for (symbol, _) in axiom.iter() {
+            counts[index(symbol)] += 1.0;
+        }
+        let mut lengths = vec![counts.iter().sum()];
+        for _ in 0..=*levels {
+            let mut next = vec![0.0f64; n];
+            for (a, count) in counts.iter().enumerate() {
+                for (b, growth) in growth[a].iter().enumerate() {
+                    next[b] += count * growth;
+                }
+            }
+            counts = next;
+            lengths.push(counts.iter().sum());
+        }
+        Ok(lengths)
+    }
+
+    /// Rewrites the axiom `levels + 1` times, the way the `lsystem` crate
+    /// counted iterations, so saved plants keep their shape. Stops early,
+    /// keeping the last result, once no rule applies anymore.
+    pub fn derive(&self, levels: &usize) -> Result<SymbolBuffer, LSystemEvaluationError> {
+        self.check_predicted_size(levels)?;
+        let mut rules = self.as_production_rules()?;
+        let mut current = parse_modules(&self.axiom.iter().collect::<String>(), RuleSource::Axiom)?;
+        let mut next = SymbolBuffer::with_capacity(current.len());
+        for iteration in 0..=*levels {
+            if !rules.rewrite(&current, &mut next, iteration)? {
+                break;
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        Ok(current)
+    }
+
+    /// Refuses up front, rather than partway through a long derivation, when
+    /// the prediction is exact and already over the symbol budget.
+    fn check_predicted_size(&self, levels: &usize) -> Result<(), LSystemEvaluationError> {
+        if !self.has_exact_prediction() {
+            return Ok(());
+        }
+        let lengths = self.predict_lengths(levels)?;
+        match lengths
+            .iter()
+            .skip(1)
+            .position(|l| *l > self.limits.max_symbols as f64)
+        {
+            Some(iteration) => Err(LSystemEvaluationError::SizeLimitExceeded {
+                iteration,
+                limit: self.limits.max_symbols,
+            }),
+            None => Ok(()),
         }
-        map_rules
     }
 
     pub fn eval(&self, levels: &usize) -> Result<String, LSystemEvaluationError> {
-        let map_rules = self.as_map_rules();
-        let mut system = LSystem::new(map_rules, self.axiom.clone());
-        let output = system
-            .nth(levels.clone())
-            .ok_or(LSystemEvaluationError::EvaluationError)?
-            .into_iter()
-            .collect();
-        Ok(output)
+        Ok(self.derive(levels)?.to_string())
+    }
+}
+
+impl ProductionRules {
+    /// Applies one derivation step to every module of `input` in parallel,
+    /// replacing the contents of `output`. Returns whether any rule applied.
+    pub(crate) fn rewrite(
+        &mut self,
+        input: &SymbolBuffer,
+        output: &mut SymbolBuffer,
+        iteration: usize,
+    ) -> Result<bool, LSystemEvaluationError> {
+        output.clear();
+        let mut expanded = false;
+        for i in 0..input.len() {
+            if !self.apply(input, i, output) {
+                output.push(input.symbol(i), input.params(i).iter().copied());
+            } else {
+                expanded = true;
+            }
+            if output.len() > self.max_symbols {
+                return Err(LSystemEvaluationError::SizeLimitExceeded {
+                    iteration,
+                    limit: self.max_symbols,
+                });
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Writes the successor of module `i` to `output`, if any rule applies.
+    fn apply(&mut self, input: &SymbolBuffer, i: usize, output: &mut SymbolBuffer) -> bool {
+        let symbol = input.symbol(i);
+        let params = input.params(i);
+        for rule in &self.parametric {
+            if rule.symbol != symbol || rule.arity != params.len() {
+                continue;
+            }
+            let Some(left) = self.match_left_context(input, i, &rule.left_context) else {
+                continue;
+            };
+            let Some(right) = self.match_right_context(input, i, &rule.right_context) else {
+                continue;
+            };
+            let args: Vec<f32> = left
+                .iter()
+                .chain(params.iter())
+                .chain(right.iter())
+                .copied()
+                .collect();
+            if let Some(condition) = &rule.condition {
+                if condition.eval(&args) == 0.0 {
+                    continue;
+                }
+            }
+            for (symbol, exprs) in &rule.successor {
+                output.push(*symbol, exprs.iter().map(|e| e.eval(&args)));
+            }
+            return true;
+        }
+        match self
+            .productions
+            .get(&symbol)
+            .and_then(|successors| pick_weighted(&mut self.rng, successors))
+        {
+            Some(successor) => {
+                output.extend_from(successor);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Walks backwards from `i` towards the root of the plant, skipping
+    /// ignored symbols and completed branches, and returns the parameters of
+    /// the matched modules in pattern order.
+    fn match_left_context(
+        &self,
+        state: &SymbolBuffer,
+        i: usize,
+        pattern: &[(char, usize)],
+    ) -> Option<Vec<f32>> {
+        let mut matched = Vec::new();
+        let mut j = i;
+        for (symbol, arity) in pattern.iter().rev() {
+            loop {
+                j = j.checked_sub(1)?;
+                match state.symbol(j) {
+                    ']' => j = matching_open_bracket(state, j)?,
+                    '[' => {}
+                    c if self.ignore.contains(&c) => {}
+                    c if c == *symbol && state.params(j).len() == *arity => {
+                        matched.push(state.params(j));
+                        break;
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        Some(matched.into_iter().rev().flatten().copied().collect())
+    }
+
+    /// Walks forwards from `i`, skipping ignored symbols and branches that
+    /// the pattern does not descend into. A `]` in the pattern skips the rest
+    /// of the current branch.
+    fn match_right_context(
+        &self,
+        state: &SymbolBuffer,
+        i: usize,
+        pattern: &[(char, usize)],
+    ) -> Option<Vec<f32>> {
+        let mut matched = Vec::new();
+        let mut j = i + 1;
+        for (symbol, arity) in pattern {
+            if *symbol == ']' {
+                j = matching_close_bracket(state, j)? + 1;
+                continue;
+            }
+            loop {
+                if j >= state.len() {
+                    return None;
+                }
+                match state.symbol(j) {
+                    '[' if *symbol == '[' => {
+                        j += 1;
+                        break;
+                    }
+                    '[' => j = matching_close_bracket(state, j + 1)? + 1,
+                    ']' => return None,
+                    c if self.ignore.contains(&c) => j += 1,
+                    c if c == *symbol && state.params(j).len() == *arity => {
+                        matched.extend_from_slice(state.params(j));
+                        j += 1;
+                        break;
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        Some(matched)
+    }
+}
+
+fn pick_weighted<'a>(
+    rng: &mut ChaCha8Rng,
+    successors: &'a [(f32, SymbolBuffer)],
+) -> Option<&'a SymbolBuffer> {
+    if successors.len() == 1 {
+        return Some(&successors[0].1);
+    }
+    let total: f32 = successors.iter().map(|(w, _)| w.max(0.0)).sum();
+    if total <= 0.0 {
+        return successors.first().map(|(_, v)| v);
+    }
+    let mut pick = rng.gen_range(0.0..total);
+    for (weight, successor) in successors {
+        let weight = weight.max(0.0);
+        if pick < weight {
+            return Some(successor);
+        }
+        pick -= weight;
+    }
+    successors.last().map(|(_, v)| v)
+}
+
+/// Index of the `[` that opens the branch closed by the `]` at `close`.
+fn matching_open_bracket(state: &SymbolBuffer, close: usize) -> Option<usize> {
+    let mut depth = 0;
+    for j in (0..close).rev() {
+        match state.symbol(j) {
+            ']' => depth += 1,
+            '[' if depth == 0 => return Some(j),
+            '[' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Index of the `]` that closes the branch containing position `from`.
+fn matching_close_bracket(state: &SymbolBuffer, from: usize) -> Option<usize> {
+    let mut depth = 0;
+    for j in from..state.len() {
+        match state.symbol(j) {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Some(j),
+            ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+impl DerivationCache {
+    /// The symbols after `levels + 1` rewrites. Returns `None` as soon as
+    /// `cancelled` is set, keeping the steps rewritten so far.
+    pub(crate) fn derive(
+        &mut self,
+        rules: &LSysRules,
+        levels: usize,
+        cancelled: Option<&AtomicBool>,
+    ) -> Option<Result<&SymbolBuffer, LSystemEvaluationError>> {
+        if self.key.as_ref() != Some(rules) {
+            *self = DerivationCache::default();
+            let compiled = rules.as_production_rules().and_then(|production_rules| {
+                let axiom =
+                    parse_modules(&rules.axiom.iter().collect::<String>(), RuleSource::Axiom)?;
+                Ok((production_rules, axiom))
+            });
+            let (production_rules, axiom) = match compiled {
+                Ok(compiled) => compiled,
+                Err(error) => return Some(Err(error)),
+            };
+            self.rules = Some(production_rules);
+            self.steps.push(axiom);
+            self.key = Some(rules.clone());
+        }
+        // Same iteration count as `LSysRules::derive`, i.e. `levels + 1` rewrites.
+        let wanted = levels + 1;
+        if self.steps.len() <= wanted && !self.finished {
+            if let Some(error) = &self.error {
+                return Some(Err(error.clone()));
+            }
+            if let Err(error) = rules.check_predicted_size(&levels) {
+                return Some(Err(error));
+            }
+        }
+        while self.steps.len() <= wanted && !self.finished {
+            if cancelled.is_some_and(|c| c.load(Ordering::Relaxed)) {
+                return None;
+            }
+            let (Some(production_rules), Some(last)) = (self.rules.as_mut(), self.steps.last())
+            else {
+                break;
+            };
+            let mut next = SymbolBuffer::with_capacity(last.len());
+            match production_rules.rewrite(last, &mut next, self.steps.len() - 1) {
+                Ok(true) => self.steps.push(next),
+                Ok(false) => self.finished = true,
+                Err(error) => {
+                    self.error = Some(error.clone());
+                    return Some(Err(error));
+                }
+            }
+        }
+        let index = wanted.min(self.steps.len() - 1);
+        Some(Ok(&self.steps[index]))
+    }
+}
+
+impl std::fmt::Debug for DerivationCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DerivationCache")
+            .field("steps", &self.steps.len())
+            .field("finished", &self.finished)
+            .finish()
     }
 }
 
@@ -68,3 +902,191 @@ impl LSysDrawer {
         Self { changed: true }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(axiom: &str, rules: &[(char, &str)]) -> LSysRules {
+        LSysRules::new(
+            axiom.chars().collect(),
+            rules.iter().map(|(k, v)| (*k, v.to_string())).collect(),
+        )
+    }
+
+    fn contextual(left: &str, predecessor: &str, right: &str, successor: &str) -> ParametricRule {
+        ParametricRule {
+            left_context: left.to_string(),
+            predecessor: predecessor.to_string(),
+            right_context: right.to_string(),
+            successor: successor.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn signal_moves_along_the_left_context() {
+        let mut signal = rules("BAAA", &[('B', "A")]);
+        signal.parametric_rules.push(contextual("B", "A", "", "B"));
+        assert_eq!(signal.eval(&0).unwrap(), "ABAA");
+        assert_eq!(signal.eval(&1).unwrap(), "AABA");
+        assert_eq!(signal.eval(&2).unwrap(), "AAAB");
+    }
+
+    #[test]
+    fn left_context_skips_branches_and_ignored_symbols() {
+        let mut plant = rules("A[+B]C", &[]);
+        plant.parametric_rules.push(contextual("A", "C", "", "X"));
+        plant.parametric_rules.push(contextual("A", "B", "", "Y"));
+        // Without the ignore set the `+` is in the way of B's context.
+        assert_eq!(plant.eval(&0).unwrap(), "A[+B]X");
+        plant.ignore = vec!['+'];
+        assert_eq!(plant.eval(&0).unwrap(), "A[+Y]X");
+    }
+
+    #[test]
+    fn right_context_descends_into_branches_only_when_asked() {
+        let mut skip = rules("A[B]C", &[]);
+        skip.parametric_rules.push(contextual("", "A", "C", "X"));
+        assert_eq!(skip.eval(&0).unwrap(), "X[B]C");
+
+        let mut descend = rules("A[B]C", &[]);
+        descend
+            .parametric_rules
+            .push(contextual("", "A", "[B]C", "X"));
+        assert_eq!(descend.eval(&0).unwrap(), "X[B]C");
+
+        let mut wrong_branch = rules("A[D]C", &[]);
+        wrong_branch
+            .parametric_rules
+            .push(contextual("", "A", "[B]C", "X"));
+        assert_eq!(wrong_branch.eval(&0).unwrap(), "A[D]C");
+
+        // The end of a branch ends its right context.
+        let mut leaf = rules("[A]B", &[]);
+        leaf.parametric_rules.push(contextual("", "A", "B", "X"));
+        assert_eq!(leaf.eval(&0).unwrap(), "[A]B");
+    }
+
+    #[test]
+    fn context_parameters_are_bound_in_order() {
+        let mut sum = rules("A(1)B(2)C(4)", &[]);
+        sum.parametric_rules.push(ParametricRule {
+            condition: "x < z".to_string(),
+            ..contextual("A(x)", "B(y)", "C(z)", "B(x + y * z)")
+        });
+        assert_eq!(sum.eval(&0).unwrap(), "A(1)B(9)C(4)");
+    }
+
+    // The next two are the examples from the `lsystem` crate, pinning down
+    // that `levels` counts like it did there, i.e. `levels + 1` rewrites.
+    #[test]
+    fn algae() {
+        let algae = rules("A", &[('A', "AB"), ('B', "A")]);
+        assert_eq!(algae.eval(&0).unwrap(), "AB");
+        assert_eq!(algae.eval(&1).unwrap(), "ABA");
+        assert_eq!(algae.eval(&2).unwrap(), "ABAAB");
+    }
+
+    #[test]
+    fn pythagoras_tree() {
+        let tree = rules("0", &[('1', "11"), ('0', "1[0]0")]);
+        assert_eq!(tree.eval(&0).unwrap(), "1[0]0");
+        assert_eq!(tree.eval(&1).unwrap(), "11[1[0]0]1[0]0");
+        assert_eq!(tree.eval(&2).unwrap(), "1111[11[1[0]0]1[0]0]11[1[0]0]1[0]0");
+    }
+
+    #[test]
+    fn stops_once_no_rule_applies() {
+        let mut countdown = rules("A(2)", &[]);
+        countdown.parametric_rules.push(ParametricRule {
+            predecessor: "A(t)".to_string(),
+            condition: "t > 0".to_string(),
+            successor: "F(t)A(t - 1)".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(countdown.eval(&0).unwrap(), "F(2)A(1)");
+        assert_eq!(countdown.eval(&1).unwrap(), "F(2)F(1)A(0)");
+        assert_eq!(countdown.eval(&5).unwrap(), "F(2)F(1)A(0)");
+    }
+
+    #[test]
+    fn rules_without_a_predecessor_are_skipped() {
+        let mut tree = rules("0", &[('1', "11"), ('0', "1[0]0")]);
+        tree.parametric_rules.push(ParametricRule::default());
+        assert_eq!(tree.eval(&1).unwrap(), "11[1[0]0]1[0]0");
+    }
+
+    #[test]
+    fn stochastic_rules_follow_the_seed() {
+        let mut weeds = rules("X", &[]);
+        weeds.stochastic_rules.push((
+            'X',
+            vec![(1.0, "F[+X]X".to_string()), (1.0, "F[-X]X".to_string())],
+        ));
+        weeds.seed = 7;
+        let first = weeds.eval(&4).unwrap();
+        assert_eq!(weeds.eval(&4).unwrap(), first);
+        assert!(first.contains('+') && first.contains('-'));
+    }
+
+    #[test]
+    fn cache_matches_a_fresh_derivation() {
+        let tree = rules("0", &[('1', "11"), ('0', "1[0]0")]);
+        let mut cache = DerivationCache::default();
+        for levels in [3, 1, 4] {
+            assert_eq!(
+                cache.derive(&tree, levels, None).unwrap().unwrap(),
+                &tree.derive(&levels).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn cancelled_derivation_keeps_its_steps() {
+        let tree = rules("0", &[('1', "11"), ('0', "1[0]0")]);
+        let mut cache = DerivationCache::default();
+        cache.derive(&tree, 1, None).unwrap().unwrap();
+        assert!(cache
+            .derive(&tree, 4, Some(&AtomicBool::new(true)))
+            .is_none());
+        assert_eq!(cache.steps.len(), 3);
+        assert_eq!(
+            cache.derive(&tree, 4, None).unwrap().unwrap(),
+            &tree.derive(&4).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors_name_their_source() {
+        assert_eq!(
+            rules("A", &[('A', "B,A")]).eval(&0),
+            Err(LSystemEvaluationError::UnknownSymbol {
+                symbol: ',',
+                source: RuleSource::Rule(0),
+            })
+        );
+        let mut unknown = rules("A(1)", &[]);
+        unknown.parametric_rules.push(ParametricRule {
+            predecessor: "A(t)".to_string(),
+            successor: "A(s)".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            unknown.eval(&0),
+            Err(LSystemEvaluationError::UnknownParameter {
+                name: "s".to_string(),
+                source: RuleSource::ParametricRule(0),
+            })
+        );
+        let mut doubling = rules("A", &[('A', "AA")]);
+        doubling.limits.max_symbols = 100;
+        assert_eq!(
+            doubling.eval(&10),
+            Err(LSystemEvaluationError::SizeLimitExceeded {
+                iteration: 6,
+                limit: 100,
+            })
+        );
+    }
+}
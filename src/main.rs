@@ -1,42 +1,33 @@
-use bevy::{
-    prelude::*,
-    render::{mesh::PrimitiveTopology, render_asset::RenderAssetUsages},
-};
+use std::process::ExitCode;
 
-use fractal_plant::{add_first_fractal_plant, add_new_fractal_plants};
-use lsys_rendering::{FractalPlantUpdateEvent, LineMaterial};
-use plant_pot::load_pot;
+use bevy::prelude::*;
+use bevy_lsystems::{LSystemPlugin, PlantSpawnPoint};
 
-use serde::{Deserialize, Serialize};
-
-mod fractal_plant;
-mod hilbert_curve;
+mod cli;
 mod lsys_egui;
-mod lsys_rendering;
-mod lsystems;
 mod pickup;
 mod plant_pot;
 mod player;
-mod save_load;
 
-fn main() {
+fn main() -> ExitCode {
+    // `headless` evaluates a plant without a window, for scripts.
+    if let Some(code) = cli::run() {
+        return code;
+    }
     App::new()
-        .add_plugins((DefaultPlugins, MaterialPlugin::<LineMaterial>::default()))
+        .add_plugins((DefaultPlugins, LSystemPlugin))
         //.add_plugins(NoCameraPlayerPlugin)
         .add_plugins((
             lsys_egui::MyEguiPlugin,
             player::MyPlayerPlugin,
             pickup::PickupPlugin,
         ))
-        .add_systems(Startup, (add_first_fractal_plant))
-        .add_systems(
-            Update,
-            (
-                add_new_fractal_plants,
-                fractal_plant::update_plant_meshes,
-                fractal_plant::update_plant_materials,
-            ),
-        )
-        .add_event::<FractalPlantUpdateEvent>()
+        .add_systems(Startup, plant_first_tree)
+        .add_systems(Update, plant_pot::pot_new_plants)
         .run();
+    ExitCode::SUCCESS
+}
+
+fn plant_first_tree(mut commands: Commands) {
+    commands.spawn(PlantSpawnPoint(Vec3::ZERO));
 }
@@ -2,10 +2,9 @@ use std::ptr;
 
 use bevy::{ecs::reflect::ReflectCommandExt, prelude::*, transform::commands};
 
-use crate::{
-    lsys_egui::SideMenuOptions,
-    player::{ActiveEntity, PlayerCam},
-};
+use bevy_lsystems::{FractalPlant, SideMenuOptions};
+
+use crate::player::PlayerCam;
 
 const PICKUP_POINT_OFFSET: f32 = 2.0;
 
@@ -68,7 +67,15 @@ impl Plugin for PickupPlugin {
             .add_systems(
                 Update,
                 (update_player_pickup_point, move_held_entity_to_hold).chain(),
-            );
+            )
+            .add_systems(Update, make_plants_candidates);
+    }
+}
+
+/// Lets every new plant be picked up.
+fn make_plants_candidates(mut commands: Commands, plants: Query<Entity, Added<FractalPlant>>) {
+    for entity in plants.iter() {
+        commands.entity(entity).insert(ActiveEntityCandidate);
     }
 }
 
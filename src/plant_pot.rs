@@ -1,4 +1,5 @@
 use bevy::prelude::*;
+use bevy_lsystems::FractalPlant;
 
 pub fn add_pot(pos: Vec3) {}
 
@@ -9,3 +10,15 @@ pub fn load_pot(mut commands: Commands, assets: Res<AssetServer>) {
         ..Default::default()
     });
 }
+
+/// Puts every new plant in a pot.
+pub fn pot_new_plants(
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    plants: Query<Entity, Added<FractalPlant>>,
+) {
+    for entity in plants.iter() {
+        let pot: Handle<Scene> = assets.load("pot.glb#Scene0");
+        commands.entity(entity).insert(pot);
+    }
+}
@@ -2,8 +2,9 @@ use bevy::{prelude::*, window::PrimaryWindow};
 use bevy_flycam::{FlyCam, KeyBindings, MovementSettings, NoCameraPlayerPlugin};
 use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
 
+use bevy_lsystems::PlantSpawnPoint;
+
 use crate::{
-    fractal_plant::PlantSpawnPoint,
     lsys_egui::PanelOccupiedScreenSpace,
     pickup::{ActiveEntityCandidate, Holder},
 };
@@ -0,0 +1,31 @@
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::VertexOutput
+#else
+#import bevy_pbr::forward_io::VertexOutput
+#endif
+#import bevy_lsystems::wind::{Vertex, Wind, swayed_vertex}
+
+struct LineMaterial {
+    color: vec4<f32>,
+};
+
+@group(2) @binding(0) var<uniform> material: LineMaterial;
+@group(2) @binding(1) var<uniform> wind: Wind;
+@group(2) @binding(2) var<uniform> growth_left: f32;
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    return swayed_vertex(vertex, wind, growth_left);
+}
+
+@fragment
+fn fragment(
+    mesh: VertexOutput,
+) -> @location(0) vec4<f32> {
+#ifdef VERTEX_COLORS
+    // Plants with a palette color each segment themselves.
+    return mesh.color;
+#else
+    return material.color;
+#endif
+}
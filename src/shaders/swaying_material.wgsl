@@ -0,0 +1,14 @@
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::VertexOutput
+#else
+#import bevy_pbr::forward_io::VertexOutput
+#endif
+#import bevy_lsystems::wind::{Vertex, Wind, swayed_vertex}
+
+@group(2) @binding(100) var<uniform> wind: Wind;
+@group(2) @binding(101) var<uniform> growth_left: f32;
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    return swayed_vertex(vertex, wind, growth_left);
+}
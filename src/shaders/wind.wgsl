@@ -0,0 +1,175 @@
+#define_import_path bevy_lsystems::wind
+
+#import bevy_pbr::{
+    mesh_functions,
+    view_transformations::position_world_to_clip,
+}
+
+// The prepass, which also draws the shadow maps, has its own vertex layout
+// and view bindings. Plants sway and grow there too, so their shadows move
+// with them.
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::VertexOutput
+#import bevy_render::globals::Globals
+
+@group(0) @binding(1) var<uniform> globals: Globals;
+#else
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::globals,
+}
+#endif
+
+struct Wind {
+    direction: vec3<f32>,
+    strength: f32,
+};
+
+// Bevy's mesh vertex input, plus the growth attribute of plant meshes.
+#ifdef PREPASS_PIPELINE
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+#ifdef VERTEX_UVS
+    @location(1) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_UVS_B
+    @location(2) uv_b: vec2<f32>,
+#endif
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+    @location(3) normal: vec3<f32>,
+#ifdef VERTEX_TANGENTS
+    @location(4) tangent: vec4<f32>,
+#endif
+#endif
+#ifdef VERTEX_COLORS
+    @location(7) color: vec4<f32>,
+#endif
+#ifdef PLANT_GROWTH
+    @location(8) growth: vec4<f32>,
+#endif
+};
+#else
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+#ifdef VERTEX_NORMALS
+    @location(1) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_UVS
+    @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_UVS_B
+    @location(3) uv_b: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(4) tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(5) color: vec4<f32>,
+#endif
+#ifdef PLANT_GROWTH
+    // Where the vertex grows from, and how many generations before the
+    // plant's last one its segment is born.
+    @location(8) growth: vec4<f32>,
+#endif
+};
+#endif
+
+// How far a vertex `height` above the plant's origin is blown aside. Plants
+// in different spots of the garden sway out of step.
+fn sway(wind: Wind, height: f32, root: vec3<f32>) -> vec3<f32> {
+    let phase = dot(root.xz, vec2<f32>(1.3, 0.7));
+    let time = globals.time;
+    let gust = 0.7 + 0.3 * sin(time * 1.9 + phase)
+        + 0.15 * sin(time * 4.3 + phase * 2.0 + height * 3.0);
+    return wind.direction * wind.strength * height * height * gust;
+}
+
+// Bevy's mesh vertex stage with the vertices bent by the wind. The bend
+// grows with the height above the mesh's origin, so the base stays put.
+// `growth_left` is how many generations the plant still has to grow; each
+// segment grows from its start during its generation.
+fn swayed_vertex(vertex: Vertex, wind: Wind, growth_left: f32) -> VertexOutput {
+    var out: VertexOutput;
+    let model = mesh_functions::get_model_matrix(vertex.instance_index);
+
+#ifdef PLANT_GROWTH
+    let grown = clamp(1.0 + vertex.growth.w - growth_left, 0.0, 1.0);
+    let position = mix(vertex.growth.xyz, vertex.position, grown);
+#else
+    let position = vertex.position;
+#endif
+
+    let world_position = mesh_functions::mesh_position_local_to_world(
+        model,
+        vec4<f32>(position, 1.0)
+    );
+    let offset = sway(wind, max(position.y, 0.0), model[3].xyz);
+    out.world_position = vec4<f32>(world_position.xyz + offset, 1.0);
+    out.position = position_world_to_clip(out.world_position.xyz);
+
+#ifdef PREPASS_PIPELINE
+#ifdef DEPTH_CLAMP_ORTHO
+    out.clip_position_unclamped = out.position;
+    out.position.z = min(out.position.z, 1.0);
+#endif
+
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(
+        vertex.normal,
+        vertex.instance_index
+    );
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_functions::mesh_tangent_local_to_world(
+        model,
+        vertex.tangent,
+        vertex.instance_index
+    );
+#endif
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    // The gusts are slow enough to leave out of the motion vectors.
+    let previous_model = mesh_functions::get_previous_model_matrix(vertex.instance_index);
+    let previous_world_position = mesh_functions::mesh_position_local_to_world(
+        previous_model,
+        vec4<f32>(position, 1.0)
+    );
+    out.previous_world_position = vec4<f32>(previous_world_position.xyz + offset, 1.0);
+#endif
+#else
+#ifdef VERTEX_NORMALS
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(
+        vertex.normal,
+        vertex.instance_index
+    );
+#endif
+
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_functions::mesh_tangent_local_to_world(
+        model,
+        vertex.tangent,
+        vertex.instance_index
+    );
+#endif
+#endif
+
+#ifdef VERTEX_UVS
+    out.uv = vertex.uv;
+#endif
+
+#ifdef VERTEX_UVS_B
+    out.uv_b = vertex.uv_b;
+#endif
+
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+
+    return out;
+}
@@ -0,0 +1,341 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use bevy::prelude::*;
+
+use crate::fractal_plant::LineList;
+
+/// Which way a plant is looked at when drawn flat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SvgProjection {
+    /// Looking along -Z, the plane planar grammars such as `1[-0]+0` draw in.
+    #[default]
+    Front,
+    /// Looking along -X.
+    Side,
+    /// Looking down, with -Z at the top of the page.
+    Top,
+    /// Looking the way the camera does, without perspective.
+    Camera,
+}
+
+impl SvgProjection {
+    pub const ALL: [SvgProjection; 4] = [
+        SvgProjection::Front,
+        SvgProjection::Side,
+        SvgProjection::Top,
+        SvgProjection::Camera,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SvgProjection::Front => "Front",
+            SvgProjection::Side => "Side",
+            SvgProjection::Top => "Top",
+            SvgProjection::Camera => "Camera",
+        }
+    }
+
+    /// Rotation of a viewer looking along its -Z with Y up, like a Bevy
+    /// camera. `camera` is the camera's rotation relative to the plant.
+    pub fn view(&self, camera: Quat) -> Quat {
+        match self {
+            SvgProjection::Front => Quat::IDENTITY,
+            SvgProjection::Side => Quat::from_rotation_y(std::f32::consts::FRAC_PI_2),
+            SvgProjection::Top => Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2),
+            SvgProjection::Camera => camera,
+        }
+    }
+}
+
+fn hex(color: Color) -> String {
+    let [r, g, b, _] = color.as_rgba_u8();
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Stroke or fill style, the same for runs of segments that get merged.
+#[derive(PartialEq)]
+struct Style {
+    color: String,
+    opacity: f32,
+    /// Stroke width in plant units, or `None` for a hairline.
+    width: Option<f32>,
+}
+
+impl Style {
+    fn new(color: Color, width: Option<f32>) -> Self {
+        Self {
+            color: hex(color),
+            opacity: color.a(),
+            width,
+        }
+    }
+
+    fn stroke(&self, precision: usize) -> String {
+        let mut attributes = format!(r#"fill="none" stroke="{}""#, self.color);
+        match self.width {
+            Some(width) => write!(attributes, r#" stroke-width="{width:.precision$}""#),
+            None => write!(
+                attributes,
+                r#" stroke-width="1" vector-effect="non-scaling-stroke""#
+            ),
+        }
+        .expect("writing to a String");
+        if self.opacity < 1.0 {
+            write!(attributes, r#" stroke-opacity="{}""#, self.opacity)
+                .expect("writing to a String");
+        }
+        attributes
+    }
+
+    fn fill(&self) -> String {
+        let mut attributes = format!(r#"fill="{}""#, self.color);
+        if self.opacity < 1.0 {
+            write!(attributes, r#" fill-opacity="{}""#, self.opacity).expect("writing to a String");
+        }
+        attributes
+    }
+}
+
+/// Draws the lines and polygons of a plant as seen from `view`, as an SVG
+/// with a viewBox fitted around them.
+///
+/// Consecutive segments of the same color and width become one `<path>`, so
+/// a plant without a palette is a handful of elements, and a lone segment a
+/// `<line>`. Lines take their colors from the palette or gradient if the
+/// turtle set them, `stroke` otherwise; polygons likewise fall back to
+/// `fill`. Surfaces such as `~L` are left out.
+pub(crate) fn write_svg(
+    out: &mut impl Write,
+    lines: &LineList,
+    view: Quat,
+    stroke: Color,
+    fill: Color,
+) -> io::Result<()> {
+    let to_view = view.inverse();
+    // SVG's y axis points down.
+    let project = |p: Vec3| {
+        let p = to_view * p;
+        Vec2::new(p.x, -p.y)
+    };
+    let segments: Vec<(Vec2, Vec2)> = lines
+        .lines
+        .iter()
+        .map(|(a, b)| (project(*a), project(*b)))
+        .collect();
+    let triangles: Vec<[Vec2; 3]> = lines.triangles.iter().map(|t| t.map(project)).collect();
+
+    let points = segments
+        .iter()
+        .flat_map(|(a, b)| [*a, *b])
+        .chain(triangles.iter().flatten().copied());
+    let (min, max) = points.fold(
+        (Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)),
+        |(min, max), p| (min.min(p), max.max(p)),
+    );
+    let (min, max) = if min.x <= max.x {
+        (min, max)
+    } else {
+        (Vec2::ZERO, Vec2::ONE)
+    };
+    let max_width = lines.widths.iter().copied().fold(0.0, f32::max);
+    let extent = (max - min).max_element().max(f32::EPSILON);
+    let margin = extent * 0.05 + max_width;
+    let (min, size) = (min - margin, max - min + 2.0 * margin);
+    // About five significant digits relative to the drawing's size.
+    let precision = (4.0 - extent.log10().floor()).clamp(0.0, 9.0) as usize;
+    // Scale the page so its longer side is 1000 px.
+    let page = size * (1000.0 / size.max_element());
+
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{:.p$} {:.p$} {:.p$} {:.p$}" width="{:.0}" height="{:.0}">"#,
+        min.x,
+        min.y,
+        size.x,
+        size.y,
+        page.x,
+        page.y,
+        p = precision
+    )?;
+    writeln!(out, r#"<g stroke-linecap="round" stroke-linejoin="round">"#)?;
+
+    let has_colors = lines.colors.len() == lines.lines.len();
+    let style_of = |i: usize| {
+        let color = if has_colors {
+            lines.colors[i][0]
+        } else {
+            stroke
+        };
+        Style::new(color, lines.widths.get(i).copied())
+    };
+    let mut i = 0;
+    while i < segments.len() {
+        let style = style_of(i);
+        let mut end = i + 1;
+        while end < segments.len() && style_of(end) == style {
+            end += 1;
+        }
+        if end - i == 1 {
+            let (a, b) = segments[i];
+            writeln!(
+                out,
+                r#"<line x1="{:.p$}" y1="{:.p$}" x2="{:.p$}" y2="{:.p$}" {}/>"#,
+                a.x,
+                a.y,
+                b.x,
+                b.y,
+                style.stroke(precision),
+                p = precision
+            )?;
+        } else {
+            let mut d = String::new();
+            let mut pen = None;
+            for &(a, b) in &segments[i..end] {
+                // Segments that continue the previous one don't need a move.
+                if pen != Some(a) {
+                    write!(d, "M{:.p$} {:.p$}", a.x, a.y, p = precision)
+                        .expect("writing to a String");
+                }
+                write!(d, "L{:.p$} {:.p$}", b.x, b.y, p = precision).expect("writing to a String");
+                pen = Some(b);
+            }
+            writeln!(out, r#"<path d="{d}" {}/>"#, style.stroke(precision))?;
+        }
+        i = end;
+    }
+    writeln!(out, "</g>")?;
+
+    // Polygons are fans of triangles, filled as one path per color so no
+    // seams show between them.
+    let has_triangle_colors = lines.triangle_colors.len() == lines.triangles.len();
+    let fill_of = |i: usize| {
+        let color = if has_triangle_colors {
+            lines.triangle_colors[i]
+        } else {
+            fill
+        };
+        Style::new(color, None)
+    };
+    let mut i = 0;
+    while i < triangles.len() {
+        let style = fill_of(i);
+        let mut d = String::new();
+        while i < triangles.len() && fill_of(i) == style {
+            let [a, b, c] = triangles[i];
+            write!(
+                d,
+                "M{:.p$} {:.p$}L{:.p$} {:.p$}L{:.p$} {:.p$}Z",
+                a.x,
+                a.y,
+                b.x,
+                b.y,
+                c.x,
+                c.y,
+                p = precision
+            )
+            .expect("writing to a String");
+            i += 1;
+        }
+        writeln!(out, r#"<path d="{d}" {}/>"#, style.fill())?;
+    }
+    writeln!(out, "</svg>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fractal_plant::FractalPlant;
+    use crate::turtle::{ColorGradient, GradientMode};
+
+    fn svg(lines: &LineList) -> String {
+        let mut out = Vec::new();
+        write_svg(&mut out, lines, Quat::IDENTITY, Color::WHITE, Color::GREEN).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Value of the first `name="..."` attribute in `svg`.
+    fn attribute<'a>(svg: &'a str, name: &str) -> &'a str {
+        let start = svg.find(&format!(r#"{name}=""#)).unwrap() + name.len() + 2;
+        &svg[start..start + svg[start..].find('"').unwrap()]
+    }
+
+    /// The stroke of every segment, in the order they are drawn.
+    fn segment_strokes(svg: &str) -> Vec<String> {
+        let mut strokes = Vec::new();
+        for element in svg.lines() {
+            if element.starts_with("<line ") {
+                strokes.push(attribute(element, "stroke").to_string());
+            } else if element.starts_with("<path ") && element.contains(r#"fill="none""#) {
+                let segments = attribute(element, "d").matches('L').count();
+                strokes.extend(vec![attribute(element, "stroke").to_string(); segments]);
+            }
+        }
+        strokes
+    }
+
+    #[test]
+    fn view_box_fits_the_plant() {
+        let lines = FractalPlant::default().lines();
+        let svg = svg(&lines);
+        let view_box: Vec<f32> = attribute(&svg, "viewBox")
+            .split(' ')
+            .map(|v| v.parse().unwrap())
+            .collect();
+        let min = Vec2::new(view_box[0], view_box[1]);
+        let max = min + Vec2::new(view_box[2], view_box[3]);
+        // Front view, with SVG's y axis pointing down.
+        let project = |p: Vec3| Vec2::new(p.x, -p.y);
+        let (mut lower, mut upper) = (Vec2::INFINITY, Vec2::NEG_INFINITY);
+        for (i, (a, b)) in lines.lines.iter().enumerate() {
+            // Round caps reach half the stroke width past the ends.
+            let cap = Vec2::splat(lines.widths[i] * 0.5);
+            for p in [project(*a), project(*b)] {
+                assert!(p.cmpge(min + cap).all() && p.cmple(max - cap).all());
+                lower = lower.min(p - cap);
+                upper = upper.max(p + cap);
+            }
+        }
+        // A margin, but not much of one.
+        let plant = upper - lower;
+        assert!((max - min).cmple(plant + plant.max_element() * 0.25).all());
+        // The longer side of the page is 1000 px.
+        let page = (attribute(&svg, "width"), attribute(&svg, "height"));
+        assert!(page.0 == "1000" || page.1 == "1000");
+    }
+
+    #[test]
+    fn lone_segments_are_lines_and_runs_are_paths() {
+        let single = LineList {
+            lines: vec![(Vec3::ZERO, Vec3::Y)],
+            ..Default::default()
+        };
+        let svg_single = svg(&single);
+        assert_eq!(svg_single.matches("<line ").count(), 1);
+        assert_eq!(svg_single.matches("<path ").count(), 0);
+
+        let lines = FractalPlant::default().lines();
+        let svg = svg(&lines);
+        let elements = svg.matches("<line ").count() + svg.matches("<path ").count();
+        assert!(svg.contains("<path "));
+        assert!(elements < lines.lines.len());
+        assert_eq!(segment_strokes(&svg).len(), lines.lines.len());
+    }
+
+    #[test]
+    fn segments_keep_their_own_colors() {
+        let lines = FractalPlant {
+            gradient: ColorGradient {
+                mode: GradientMode::Depth,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .lines();
+        assert_eq!(lines.colors.len(), lines.lines.len());
+        let expected: Vec<String> = lines.colors.iter().map(|[start, _]| hex(*start)).collect();
+        assert!(expected.iter().any(|c| *c != expected[0]));
+        assert_eq!(segment_strokes(&svg(&lines)), expected);
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+
+use crate::fractal_plant::LineList;
+use crate::lsys_rendering::ATTRIBUTE_GROWTH;
+
+/// Radius used for lines that don't carry a width.
+const DEFAULT_RADIUS: f32 = 0.005;
+
+/// Where a segment ends, used to find the segments that continue it.
+/// Turtle positions are copied, never recomputed, so bitwise equality is
+/// enough.
+fn point_key(p: Vec3) -> [u32; 3] {
+    [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]
+}
+
+/// Sweeps a ring with `sides` corners along every line, giving a triangle
+/// mesh with normals and UVs for `StandardMaterial`, and `ATTRIBUTE_GROWTH`
+/// when the lines have births.
+///
+/// Every segment gets a ring at both ends. A segment that continues another
+/// starts with a copy of the other's end ring, tilted halfway between both
+/// directions and with the other's radius, so the tube bends and tapers
+/// without gaps or steps. Side branches are tilted the same way, which
+/// tucks their base into the parent, but start at their own radius. The
+/// ring's orientation is carried along each path (parallel transport) so
+/// tubes don't twist.
+pub(crate) fn tube_mesh(lines: &LineList, sides: usize) -> Mesh {
+    let sides = sides.max(3);
+    let ring_len = sides + 1;
+    let mut positions = Vec::<Vec3>::with_capacity(lines.lines.len() * ring_len * 2);
+    let mut normals = Vec::<Vec3>::with_capacity(positions.capacity());
+    let mut uvs = Vec::<Vec2>::with_capacity(positions.capacity());
+    let has_colors = !lines.colors.is_empty() && lines.colors.len() == lines.lines.len();
+    let mut colors = Vec::<[f32; 4]>::new();
+    // The end ring grows from the start ring.
+    let before_last = lines.generations_before_last();
+    let mut growth = Vec::<[f32; 4]>::new();
+    let mut indices = Vec::<u32>::with_capacity(lines.lines.len() * sides * 6);
+
+    // Last segment starting at each point, the one that continues a path:
+    // the turtle draws the branches in brackets before it.
+    let mut last_starting_at = HashMap::<[u32; 3], usize>::new();
+    for (i, (start, _)) in lines.lines.iter().enumerate() {
+        last_starting_at.insert(point_key(*start), i);
+    }
+    // Latest segment ending at each point, with the ring reference vector,
+    // texture coordinate and radius it ended with.
+    let mut ending_at = HashMap::<[u32; 3], (Vec3, Vec3, f32, f32)>::new();
+
+    for (i, (start, end)) in lines.lines.iter().enumerate() {
+        let length = start.distance(*end);
+        if length <= f32::EPSILON {
+            continue;
+        }
+        let dir = (*end - *start) / length;
+        let radius = lines
+            .widths
+            .get(i)
+            .map_or(DEFAULT_RADIUS, |w| (w * 0.5).max(0.0));
+
+        let parent = ending_at.get(&point_key(*start)).copied();
+        let start_axis = parent.map_or(dir, |(parent_dir, _, _, _)| {
+            (parent_dir + dir).try_normalize().unwrap_or(dir)
+        });
+        let end_axis = last_starting_at
+            .get(&point_key(*end))
+            .filter(|&&next| next > i)
+            .and_then(|&next| {
+                let (a, b) = lines.lines[next];
+                (b - a).try_normalize()
+            })
+            .map_or(dir, |next_dir| {
+                (dir + next_dir).try_normalize().unwrap_or(dir)
+            });
+
+        let reference = parent.map_or_else(|| dir.any_orthonormal_vector(), |(_, r, _, _)| r);
+        let start_reference = perpendicular(reference, start_axis);
+        let end_reference = perpendicular(start_reference, end_axis);
+        let v_start = parent.map_or(0.0, |(_, _, v, _)| v);
+        let v_end = v_start + length;
+        let continues = last_starting_at.get(&point_key(*start)) == Some(&i);
+        let start_radius = parent
+            .filter(|_| continues)
+            .map_or(radius, |(_, _, _, r)| r);
+
+        let base = positions.len() as u32;
+        for (end_index, (center, axis, reference, v, radius)) in [
+            (*start, start_axis, start_reference, v_start, start_radius),
+            (*end, end_axis, end_reference, v_end, radius),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let bitangent = axis.cross(reference);
+            for k in 0..ring_len {
+                let angle = k as f32 / sides as f32 * TAU;
+                let normal = reference * angle.cos() + bitangent * angle.sin();
+                let position = center + normal * radius;
+                if let Some(before_last) = &before_last {
+                    let origin = match end_index {
+                        0 => position,
+                        _ => positions[base as usize + k],
+                    };
+                    growth.push(origin.extend(before_last[i]).to_array());
+                }
+                positions.push(position);
+                normals.push(normal);
+                uvs.push(Vec2::new(k as f32 / sides as f32, v));
+                if has_colors {
+                    colors.push(lines.colors[i][end_index].as_linear_rgba_f32());
+                }
+            }
+        }
+        for k in 0..sides as u32 {
+            let a = base + k;
+            let b = base + ring_len as u32 + k;
+            indices.extend_from_slice(&[a, a + 1, b, a + 1, b + 1, b]);
+        }
+
+        ending_at.insert(point_key(*end), (dir, end_reference, v_end, radius));
+    }
+
+    let mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+    .with_inserted_indices(Indices::U32(indices));
+    let mesh = if before_last.is_some() {
+        mesh.with_inserted_attribute(ATTRIBUTE_GROWTH, growth)
+    } else {
+        mesh
+    };
+    if has_colors {
+        mesh.with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)
+    } else {
+        mesh
+    }
+}
+
+/// `v` projected onto the plane with normal `axis`, normalized. Falls back
+/// to any perpendicular vector when `v` is parallel to `axis`.
+fn perpendicular(v: Vec3, axis: Vec3) -> Vec3 {
+    (v - axis * v.dot(axis))
+        .try_normalize()
+        .unwrap_or_else(|| axis.any_orthonormal_vector())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::mesh::VertexAttributeValues;
+
+    #[test]
+    fn tapered_joint_continues_the_parent_ring() {
+        let lines = LineList {
+            lines: vec![(Vec3::ZERO, Vec3::Y), (Vec3::Y, Vec3::new(0.0, 2.0, 0.0))],
+            widths: vec![0.2, 0.1],
+            ..Default::default()
+        };
+        let sides = 6;
+        let ring_len = sides + 1;
+        let mesh = tube_mesh(&lines, sides);
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            panic!("tube mesh without positions");
+        };
+        assert_eq!(positions.len(), 4 * ring_len);
+        let parent_end = &positions[ring_len..2 * ring_len];
+        let child_start = &positions[2 * ring_len..3 * ring_len];
+        assert_eq!(parent_end, child_start);
+        let child_end = Vec3::from(positions[3 * ring_len]);
+        assert!((child_end.distance(Vec3::new(0.0, 2.0, 0.0)) - 0.05).abs() < 1e-5);
+    }
+
+    #[test]
+    fn side_branches_start_at_their_own_radius() {
+        let fork = Vec3::Y;
+        let lines = LineList {
+            // `F[+F]F`, with the branch narrowed by the bracket.
+            lines: vec![
+                (Vec3::ZERO, fork),
+                (fork, Vec3::new(-1.0, 1.0, 0.0)),
+                (fork, Vec3::new(0.0, 2.0, 0.0)),
+            ],
+            widths: vec![0.2, 0.1, 0.2],
+            ..Default::default()
+        };
+        let sides = 6;
+        let ring_len = sides + 1;
+        let mesh = tube_mesh(&lines, sides);
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            panic!("tube mesh without positions");
+        };
+        let branch_start = &positions[2 * ring_len..3 * ring_len];
+        for p in branch_start {
+            assert!((Vec3::from(*p).distance(fork) - 0.05).abs() < 1e-5);
+        }
+        // The trunk carries on with the parent's end ring.
+        let parent_end = &positions[ring_len..2 * ring_len];
+        let trunk_start = &positions[4 * ring_len..5 * ring_len];
+        assert_eq!(parent_end, trunk_start);
+    }
+
+    #[test]
+    fn segments_grow_from_their_start_ring() {
+        let lines = LineList {
+            lines: vec![(Vec3::ZERO, Vec3::Y), (Vec3::Y, Vec3::new(0.0, 2.0, 0.0))],
+            births: vec![0, 1],
+            ..Default::default()
+        };
+        let sides = 4;
+        let ring_len = sides + 1;
+        let mesh = tube_mesh(&lines, sides);
+        let (
+            Some(VertexAttributeValues::Float32x3(positions)),
+            Some(VertexAttributeValues::Float32x4(growth)),
+        ) = (
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION),
+            mesh.attribute(ATTRIBUTE_GROWTH),
+        )
+        else {
+            panic!("tube mesh without positions or growth");
+        };
+        for k in 0..ring_len {
+            let first_start = positions[k];
+            assert_eq!(
+                growth[ring_len + k],
+                [first_start[0], first_start[1], first_start[2], 1.0]
+            );
+            let second_start = positions[2 * ring_len + k];
+            assert_eq!(growth[2 * ring_len + k][..3], second_start);
+            assert_eq!(growth[3 * ring_len + k][..3], second_start);
+            assert_eq!(growth[3 * ring_len + k][3], 0.0);
+        }
+    }
+}
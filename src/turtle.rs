@@ -0,0 +1,737 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::fractal_plant::{LineList, SurfaceInstance};
+use crate::lsystems::{LSystemEvaluationError, SymbolBuffer};
+
+/// Position and orientation of the turtle. The frame is the heading/left/up
+/// triple from "The Algorithmic Beauty of Plants", kept orthonormal with
+/// `heading x left = up`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TurtleState {
+    pub(crate) pos: Vec3,
+    pub(crate) heading: Vec3,
+    pub(crate) left: Vec3,
+    pub(crate) up: Vec3,
+    /// Width of the segments drawn from here on.
+    pub(crate) width: f32,
+    /// Index into the palette for everything drawn from here on.
+    pub(crate) color: usize,
+    /// Length of the path from the root to here.
+    pub(crate) distance: f32,
+    /// Number of segments drawn on the path from the root to here.
+    pub(crate) generation: u32,
+}
+
+/// What the turtle does when it reads a symbol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TurtleCommand {
+    /// Move forward and draw.
+    Draw,
+    /// Draw a segment without moving.
+    DrawInPlace,
+    /// Move forward without drawing.
+    Move,
+    TurnLeft,
+    TurnRight,
+    PitchDown,
+    PitchUp,
+    RollLeft,
+    RollRight,
+    TurnAround,
+    /// Roll until left is horizontal.
+    RollToVertical,
+    Push,
+    Pop,
+    /// Set the width to the module parameter, or narrow it by the taper
+    /// without one.
+    SetWidth,
+    /// Use the next palette color, or the one given by the module parameter.
+    NextColor,
+    /// Start a polygon; vertices are recorded until the matching
+    /// `EndPolygon`.
+    StartPolygon,
+    /// Record the turtle position as a polygon vertex.
+    RecordVertex,
+    EndPolygon,
+    /// Place the predefined surface named by the next symbol, e.g. `~L`.
+    Surface,
+    /// Only takes part in rewriting, e.g. `X` in `X -> F[+X]F[-X]+X`.
+    Ignore,
+}
+
+impl TurtleCommand {
+    pub(crate) const ALL: [TurtleCommand; 20] = [
+        TurtleCommand::Draw,
+        TurtleCommand::DrawInPlace,
+        TurtleCommand::Move,
+        TurtleCommand::TurnLeft,
+        TurtleCommand::TurnRight,
+        TurtleCommand::PitchDown,
+        TurtleCommand::PitchUp,
+        TurtleCommand::RollLeft,
+        TurtleCommand::RollRight,
+        TurtleCommand::TurnAround,
+        TurtleCommand::RollToVertical,
+        TurtleCommand::Push,
+        TurtleCommand::Pop,
+        TurtleCommand::SetWidth,
+        TurtleCommand::NextColor,
+        TurtleCommand::StartPolygon,
+        TurtleCommand::RecordVertex,
+        TurtleCommand::EndPolygon,
+        TurtleCommand::Surface,
+        TurtleCommand::Ignore,
+    ];
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            TurtleCommand::Draw => "Draw",
+            TurtleCommand::DrawInPlace => "Draw in place",
+            TurtleCommand::Move => "Move",
+            TurtleCommand::TurnLeft => "Turn left",
+            TurtleCommand::TurnRight => "Turn right",
+            TurtleCommand::PitchDown => "Pitch down",
+            TurtleCommand::PitchUp => "Pitch up",
+            TurtleCommand::RollLeft => "Roll left",
+            TurtleCommand::RollRight => "Roll right",
+            TurtleCommand::TurnAround => "Turn around",
+            TurtleCommand::RollToVertical => "Roll to vertical",
+            TurtleCommand::Push => "Push",
+            TurtleCommand::Pop => "Pop",
+            TurtleCommand::SetWidth => "Set width",
+            TurtleCommand::NextColor => "Next color",
+            TurtleCommand::StartPolygon => "Start polygon",
+            TurtleCommand::RecordVertex => "Record vertex",
+            TurtleCommand::EndPolygon => "End polygon",
+            TurtleCommand::Surface => "Surface",
+            TurtleCommand::Ignore => "Ignore",
+        }
+    }
+}
+
+/// Maps symbols to turtle commands, symbols without an entry are ignored.
+/// Saved with the `LSys`; configurations from before the table existed get
+/// the default one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Interpretation {
+    pub commands: Vec<(char, TurtleCommand)>,
+}
+
+/// The standard symbols from "The Algorithmic Beauty of Plants":
+///
+/// | symbol | command |
+/// |--------|---------|
+/// | `F`    | move forward and draw |
+/// | `f`    | move forward without drawing |
+/// | `+` `-` | turn left/right around up |
+/// | `&` `^` | pitch down/up around left |
+/// | `\` `/` | roll left/right around heading |
+/// | `\|`   | turn around |
+/// | `$`    | roll until left is horizontal |
+/// | `[` `]` | push/pop the state |
+/// | `!`    | set or narrow the width |
+/// | `'`    | next palette color, or the given index |
+/// | `{` `}` | start/end a polygon |
+/// | `.`    | record a polygon vertex, `f` does too inside a polygon |
+/// | `~`    | place the surface named by the next symbol |
+///
+/// Plants saved before the turtle was shared also use `1` (like `F`),
+/// `0` (draw without moving), and `<` `>` (like `&` `^`).
+impl Default for Interpretation {
+    fn default() -> Self {
+        Self {
+            commands: vec![
+                ('F', TurtleCommand::Draw),
+                ('f', TurtleCommand::Move),
+                ('+', TurtleCommand::TurnLeft),
+                ('-', TurtleCommand::TurnRight),
+                ('&', TurtleCommand::PitchDown),
+                ('^', TurtleCommand::PitchUp),
+                ('\\', TurtleCommand::RollLeft),
+                ('/', TurtleCommand::RollRight),
+                ('|', TurtleCommand::TurnAround),
+                ('$', TurtleCommand::RollToVertical),
+                ('[', TurtleCommand::Push),
+                (']', TurtleCommand::Pop),
+                ('!', TurtleCommand::SetWidth),
+                ('\'', TurtleCommand::NextColor),
+                ('{', TurtleCommand::StartPolygon),
+                ('.', TurtleCommand::RecordVertex),
+                ('}', TurtleCommand::EndPolygon),
+                ('~', TurtleCommand::Surface),
+                ('1', TurtleCommand::Draw),
+                ('0', TurtleCommand::DrawInPlace),
+                ('<', TurtleCommand::PitchDown),
+                ('>', TurtleCommand::PitchUp),
+            ],
+        }
+    }
+}
+
+impl Interpretation {
+    /// A letter without a command yet, for new rows in the side panel, so
+    /// that adding one doesn't override what an existing symbol does.
+    pub(crate) fn unused_symbol(&self) -> Option<char> {
+        ('A'..='Z')
+            .chain('a'..='z')
+            .find(|c| self.commands.iter().all(|(symbol, _)| symbol != c))
+    }
+}
+
+/// Interprets a derivation as turtle commands looked up in `interpretation`.
+/// A module parameter overrides the step length or angle, e.g. `F(0.2)` or
+/// `+(30)` with the angle in degrees.
+#[derive(Debug, Clone)]
+pub(crate) struct Turtle {
+    pub(crate) start: TurtleState,
+    pub(crate) step: f32,
+    /// Default turning angle in radians.
+    pub(crate) angle: f32,
+    pub(crate) max_segments: usize,
+    pub(crate) interpretation: Interpretation,
+    pub(crate) tropism: Tropism,
+    /// Width factor applied on every `[`, so that branches get thinner
+    /// the deeper they are nested, and on `!` without a parameter.
+    pub(crate) width_taper: f32,
+    /// Colors picked with `'`. Indices past the end use the last color;
+    /// without a palette no colors are recorded.
+    pub(crate) palette: Vec<Color>,
+    /// Overrides the palette unless it is off.
+    pub(crate) gradient: ColorGradient,
+}
+
+/// What a `ColorGradient` is spread over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) enum GradientMode {
+    #[default]
+    Off,
+    /// Bracket nesting level, from the trunk to the deepest twig.
+    Depth,
+    /// Path length from the root to the farthest tip.
+    Distance,
+}
+
+/// Color stops from 0 at the root to 1 at the deepest or farthest point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ColorGradient {
+    pub(crate) mode: GradientMode,
+    pub(crate) stops: Vec<(f32, Color)>,
+}
+
+impl Default for ColorGradient {
+    fn default() -> Self {
+        Self {
+            mode: GradientMode::Off,
+            stops: vec![
+                (0.0, Color::rgb(0.4, 0.25, 0.1)),
+                (1.0, Color::rgb(0.5, 0.9, 0.3)),
+            ],
+        }
+    }
+}
+
+impl ColorGradient {
+    /// Interpolates linearly between the stops around `t`, which don't have
+    /// to be sorted.
+    pub(crate) fn sample(&self, t: f32) -> Color {
+        let below = self
+            .stops
+            .iter()
+            .filter(|(pos, _)| *pos <= t)
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+        let above = self
+            .stops
+            .iter()
+            .filter(|(pos, _)| *pos >= t)
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        match (below, above) {
+            (Some((a_pos, a)), Some((b_pos, b))) if b_pos > a_pos => {
+                let f = (t - a_pos) / (b_pos - a_pos);
+                let a = Vec4::from(a.as_linear_rgba_f32());
+                let b = Vec4::from(b.as_linear_rgba_f32());
+                Color::rgba_linear_from_array(a.lerp(b, f).to_array())
+            }
+            (Some((_, c)), _) | (None, Some((_, c))) => *c,
+            (None, None) => Color::WHITE,
+        }
+    }
+}
+
+/// Bends the heading toward `direction` after every drawn segment, e.g.
+/// down for branches drooping under gravity or up toward light.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Tropism {
+    pub(crate) direction: Vec3,
+    /// How easily segments bend; 0 leaves the turtle rigid.
+    pub(crate) susceptibility: f32,
+}
+
+/// Width of the trunk and how fast branches narrow.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BranchWidth {
+    pub(crate) base: f32,
+    pub(crate) taper: f32,
+}
+
+impl Default for BranchWidth {
+    fn default() -> Self {
+        Self {
+            base: 0.02,
+            taper: 0.7,
+        }
+    }
+}
+
+impl Default for Tropism {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::NEG_Y,
+            susceptibility: 0.0,
+        }
+    }
+}
+
+impl TurtleState {
+    /// A turtle at `pos` whose frame is the world frame rotated by
+    /// `rotation`: heading along +Y, left along -X and up along +Z.
+    pub(crate) fn from_rotation(pos: Vec3, rotation: Quat, width: f32) -> Self {
+        Self {
+            pos,
+            heading: rotation * Vec3::Y,
+            left: rotation * Vec3::NEG_X,
+            up: rotation * Vec3::Z,
+            width,
+            color: 0,
+            distance: 0.0,
+            generation: 0,
+        }
+    }
+
+    /// Rotates the whole frame by `rotation`, renormalizing so that rounding
+    /// errors don't pile up over long strings.
+    fn rotate(&mut self, rotation: Quat) {
+        self.heading = (rotation * self.heading).normalize();
+        self.left = (rotation * self.left).normalize();
+        self.up = self.heading.cross(self.left).normalize();
+    }
+
+    /// Turns left around the up vector for positive angles.
+    pub(crate) fn turn(&mut self, angle: f32) {
+        self.rotate(Quat::from_axis_angle(self.up, angle));
+    }
+
+    /// Pitches down around the left vector for positive angles.
+    pub(crate) fn pitch(&mut self, angle: f32) {
+        self.rotate(Quat::from_axis_angle(self.left, angle));
+    }
+
+    /// Rolls left around the heading for positive angles.
+    pub(crate) fn roll(&mut self, angle: f32) {
+        self.rotate(Quat::from_axis_angle(self.heading, angle));
+    }
+
+    /// Rolls around the heading so that the left vector is horizontal.
+    /// Leaves the frame alone when heading straight up or down.
+    pub(crate) fn roll_to_vertical(&mut self) {
+        let left = Vec3::Y.cross(self.heading);
+        if left.length_squared() > 1e-8 {
+            self.left = left.normalize();
+            self.up = self.heading.cross(self.left).normalize();
+        }
+    }
+
+    /// Rotates the frame around `heading x direction` by
+    /// `susceptibility * |heading x direction|`, as in ABOP section 2.4.
+    pub(crate) fn bend(&mut self, tropism: &Tropism) {
+        let axis = self.heading.cross(tropism.direction);
+        let strength = axis.length();
+        if tropism.susceptibility != 0.0 && strength > 1e-6 {
+            self.rotate(Quat::from_axis_angle(
+                axis / strength,
+                tropism.susceptibility * strength,
+            ));
+        }
+    }
+
+    /// Rotation from surface space, where +Y is the heading, +Z is up and
+    /// -X is left, into the turtle frame.
+    fn rotation(&self) -> Quat {
+        Quat::from_mat3(&Mat3::from_cols(-self.left, self.heading, self.up))
+    }
+
+    fn forward(&self, length: f32) -> Vec3 {
+        self.pos + self.heading * length
+    }
+}
+
+impl Turtle {
+    fn color(&self, index: usize) -> Option<Color> {
+        self.palette.get(index).or(self.palette.last()).copied()
+    }
+
+    /// The value the gradient is spread over at a point, normalized once
+    /// the whole plant has been walked.
+    fn gradient_value(&self, depth: usize, distance: f32) -> f32 {
+        match self.gradient.mode {
+            GradientMode::Depth => depth as f32,
+            GradientMode::Distance | GradientMode::Off => distance,
+        }
+    }
+
+    /// Walks the turtle over `symbols`. Returns `None` as soon as
+    /// `cancelled` is set.
+    pub(crate) fn interpret(
+        &self,
+        symbols: &SymbolBuffer,
+        cancelled: Option<&AtomicBool>,
+    ) -> Option<Result<LineList, LSystemEvaluationError>> {
+        let mut lines = Vec::<(Vec3, Vec3)>::new();
+        let mut widths = Vec::<f32>::new();
+        let mut births = Vec::<u32>::new();
+        let mut colors = Vec::<[Color; 2]>::new();
+        let mut triangles = Vec::<[Vec3; 3]>::new();
+        let mut triangle_colors = Vec::<Color>::new();
+        let mut surfaces = Vec::<SurfaceInstance>::new();
+        let use_gradient = self.gradient.mode != GradientMode::Off;
+        // Gradient values at the start and end of each line and of each
+        // triangle, only recorded while the gradient is in use.
+        let mut line_values = Vec::<[f32; 2]>::new();
+        let mut triangle_values = Vec::<f32>::new();
+        // Polygons can nest, e.g. a petal started inside a leaf.
+        let mut polygons: Vec<Vec<Vec3>> = Vec::new();
+        // Size of a surface requested by `~` and waiting for its name.
+        let mut pending_surface: Option<f32> = None;
+        let mut state = self.start;
+        let mut stack: Vec<TurtleState> = Vec::new();
+        // Later entries win, like inserting them one by one in the side panel.
+        let commands: HashMap<char, TurtleCommand> =
+            self.interpretation.commands.iter().copied().collect();
+
+        for (symbol, args) in symbols.iter() {
+            if cancelled.is_some_and(|c| c.load(Ordering::Relaxed)) {
+                return None;
+            }
+            if lines.len() + triangles.len() + surfaces.len() > self.max_segments {
+                return Some(Err(LSystemEvaluationError::SegmentLimitExceeded {
+                    limit: self.max_segments,
+                }));
+            }
+            let length = args.first().copied().unwrap_or(self.step);
+            let angle = args.first().map_or(self.angle, |a| a.to_radians());
+            if let Some(scale) = pending_surface.take() {
+                // `~L(0.5)` sizes the surface itself, `~(0.5)L` works too.
+                surfaces.push(SurfaceInstance {
+                    symbol,
+                    position: state.pos,
+                    rotation: state.rotation(),
+                    scale: args.first().copied().unwrap_or(scale),
+                });
+                continue;
+            }
+            let Some(command) = commands.get(&symbol) else {
+                continue;
+            };
+            match command {
+                TurtleCommand::Draw => {
+                    let new_pos = state.forward(length);
+                    lines.push((state.pos, new_pos));
+                    widths.push(state.width);
+                    births.push(state.generation);
+                    colors.extend(self.color(state.color).map(|c| [c; 2]));
+                    if use_gradient {
+                        line_values.push([
+                            self.gradient_value(stack.len(), state.distance),
+                            self.gradient_value(stack.len(), state.distance + length),
+                        ]);
+                    }
+                    state.pos = new_pos;
+                    state.distance += length;
+                    state.generation += 1;
+                    state.bend(&self.tropism);
+                }
+                TurtleCommand::DrawInPlace => {
+                    lines.push((state.pos, state.forward(length)));
+                    widths.push(state.width);
+                    births.push(state.generation);
+                    colors.extend(self.color(state.color).map(|c| [c; 2]));
+                    if use_gradient {
+                        line_values.push([
+                            self.gradient_value(stack.len(), state.distance),
+                            self.gradient_value(stack.len(), state.distance + length),
+                        ]);
+                    }
+                }
+                TurtleCommand::Move => {
+                    state.pos = state.forward(length);
+                    state.distance += length;
+                    record_vertex(&mut polygons, state.pos);
+                }
+                TurtleCommand::TurnLeft => state.turn(angle),
+                TurtleCommand::TurnRight => state.turn(-angle),
+                TurtleCommand::PitchDown => state.pitch(angle),
+                TurtleCommand::PitchUp => state.pitch(-angle),
+                TurtleCommand::RollLeft => state.roll(angle),
+                TurtleCommand::RollRight => state.roll(-angle),
+                TurtleCommand::TurnAround => state.turn(std::f32::consts::PI),
+                TurtleCommand::RollToVertical => state.roll_to_vertical(),
+                TurtleCommand::Push => {
+                    stack.push(state);
+                    state.width *= self.width_taper;
+                }
+                TurtleCommand::Pop => state = stack.pop().unwrap_or(state),
+                TurtleCommand::SetWidth => {
+                    state.width = args.first().map_or(state.width * self.width_taper, |w| *w)
+                }
+                TurtleCommand::NextColor => {
+                    state.color = args
+                        .first()
+                        .map_or(state.color + 1, |c| c.max(0.0) as usize)
+                }
+                TurtleCommand::StartPolygon => polygons.push(Vec::new()),
+                TurtleCommand::RecordVertex => {
+                    record_vertex(&mut polygons, state.pos);
+                }
+                TurtleCommand::EndPolygon => {
+                    // Polygons from the literature are convex, a fan is enough.
+                    if let Some(polygon) = polygons.pop() {
+                        for i in 1..polygon.len().saturating_sub(1) {
+                            triangles.push([polygon[0], polygon[i], polygon[i + 1]]);
+                            triangle_colors.extend(self.color(state.color));
+                            if use_gradient {
+                                triangle_values
+                                    .push(self.gradient_value(stack.len(), state.distance));
+                            }
+                        }
+                    }
+                }
+                TurtleCommand::Surface => pending_surface = Some(length),
+                TurtleCommand::Ignore => {}
+            }
+        }
+
+        if use_gradient {
+            let max = line_values
+                .iter()
+                .flatten()
+                .chain(&triangle_values)
+                .fold(0.0f32, |max, v| max.max(*v));
+            let t = |v: f32| if max > 0.0 { v / max } else { 0.0 };
+            colors = line_values
+                .iter()
+                .map(|[a, b]| [self.gradient.sample(t(*a)), self.gradient.sample(t(*b))])
+                .collect();
+            triangle_colors = triangle_values
+                .iter()
+                .map(|v| self.gradient.sample(t(*v)))
+                .collect();
+        }
+
+        Some(Ok(LineList {
+            lines,
+            widths,
+            births,
+            colors,
+            triangles,
+            triangle_colors,
+            surfaces,
+        }))
+    }
+}
+
+/// Adds `pos` to the innermost open polygon, skipping it if it repeats the
+/// last vertex, as it does for the common `f.`.
+fn record_vertex(polygons: &mut [Vec<Vec3>], pos: Vec3) {
+    if let Some(polygon) = polygons.last_mut() {
+        if polygon.last() != Some(&pos) {
+            polygon.push(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsystems::{parse_modules, RuleSource};
+    use std::f32::consts::FRAC_PI_2;
+
+    /// A turtle at the origin heading up +Y with unit steps, right angles
+    /// and unit width.
+    fn turtle() -> Turtle {
+        Turtle {
+            start: TurtleState::from_rotation(Vec3::ZERO, Quat::IDENTITY, 1.0),
+            step: 1.0,
+            angle: FRAC_PI_2,
+            max_segments: 1000,
+            interpretation: Interpretation::default(),
+            tropism: Tropism::default(),
+            width_taper: 0.5,
+            palette: Vec::new(),
+            gradient: ColorGradient::default(),
+        }
+    }
+
+    fn draw(turtle: &Turtle, symbols: &str) -> LineList {
+        let symbols = parse_modules(symbols, RuleSource::Axiom).unwrap();
+        turtle.interpret(&symbols, None).unwrap().unwrap()
+    }
+
+    /// End of the last segment drawn.
+    fn end(turtle: &Turtle, symbols: &str) -> Vec3 {
+        draw(turtle, symbols).lines.last().unwrap().1
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!(a.abs_diff_eq(b, 1e-5), "{a} != {b}");
+    }
+
+    fn assert_orthonormal(state: &TurtleState) {
+        for v in [state.heading, state.left, state.up] {
+            assert!((v.length() - 1.0).abs() < 1e-5);
+        }
+        assert!(state.heading.dot(state.left).abs() < 1e-5);
+        assert_near(state.heading.cross(state.left), state.up);
+    }
+
+    #[test]
+    fn new_commands_get_an_unused_symbol() {
+        let mut interpretation = Interpretation::default();
+        assert_eq!(interpretation.unused_symbol(), Some('A'));
+        interpretation.commands.push(('A', TurtleCommand::Draw));
+        assert_eq!(interpretation.unused_symbol(), Some('B'));
+    }
+
+    #[test]
+    fn rotations_turn_the_heading() {
+        let turtle = turtle();
+        assert_near(end(&turtle, "F"), Vec3::Y);
+        assert_near(end(&turtle, "F+F"), Vec3::new(-1.0, 1.0, 0.0));
+        assert_near(end(&turtle, "F-F"), Vec3::new(1.0, 1.0, 0.0));
+        assert_near(end(&turtle, "&F"), Vec3::NEG_Z);
+        assert_near(end(&turtle, "^F"), Vec3::Z);
+        assert_near(end(&turtle, "|F"), Vec3::NEG_Y);
+        // Rolling leaves the heading alone but changes where pitching goes.
+        assert_near(end(&turtle, "\\F"), Vec3::Y);
+        assert_near(end(&turtle, "\\&F"), Vec3::NEG_X);
+        assert_near(end(&turtle, "/&F"), Vec3::X);
+        // A parameter overrides the angle, in degrees, and the step.
+        assert_near(end(&turtle, "+(180)F(2)"), Vec3::new(0.0, -2.0, 0.0));
+    }
+
+    #[test]
+    fn frame_stays_orthonormal() {
+        let mut state = turtle().start;
+        let tropism = Tropism {
+            susceptibility: 0.3,
+            ..Default::default()
+        };
+        for _ in 0..1000 {
+            state.turn(0.3);
+            state.pitch(0.7);
+            state.roll(1.1);
+            state.bend(&tropism);
+        }
+        assert_orthonormal(&state);
+    }
+
+    #[test]
+    fn roll_to_vertical_levels_the_left_vector() {
+        let mut state = turtle().start;
+        state.roll(0.4);
+        state.pitch(0.6);
+        let heading = state.heading;
+        state.roll_to_vertical();
+        assert_near(state.heading, heading);
+        assert!(state.left.y.abs() < 1e-5);
+        assert_orthonormal(&state);
+
+        // Heading straight up there is no horizontal left to pick.
+        let mut upright = turtle().start;
+        upright.roll(0.4);
+        let before = upright;
+        upright.roll_to_vertical();
+        assert_eq!(upright, before);
+        assert_near(end(&turtle(), "F$F"), Vec3::new(0.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn pop_restores_the_state() {
+        let turtle = turtle();
+        let lines = draw(&turtle, "[+F]F");
+        assert_near(lines.lines[0].1, Vec3::NEG_X);
+        assert_eq!(lines.lines[1], (Vec3::ZERO, Vec3::Y));
+        let lines = draw(&turtle, "[F]F");
+        assert_eq!(lines.lines[0], lines.lines[1]);
+        // Widths narrow inside brackets and come back after them.
+        assert_eq!(lines.widths, vec![0.5, 1.0]);
+    }
+
+    #[test]
+    fn widths_narrow_with_depth_and_bang() {
+        let turtle = turtle();
+        assert_eq!(draw(&turtle, "F[F[F]]").widths, vec![1.0, 0.5, 0.25]);
+        assert_eq!(draw(&turtle, "!F!(0.3)F").widths, vec![0.5, 0.3]);
+    }
+
+    #[test]
+    fn tropism_bends_toward_its_direction() {
+        let turtle = Turtle {
+            tropism: Tropism {
+                direction: Vec3::NEG_Y,
+                susceptibility: 0.2,
+            },
+            ..turtle()
+        };
+        // Heading along +X the whole pull of gravity applies.
+        let lines = draw(&turtle, "-FF");
+        assert_near(lines.lines[0].1, Vec3::X);
+        let heading = lines.lines[1].1 - lines.lines[1].0;
+        assert_near(heading, Vec3::new(0.2f32.cos(), -0.2f32.sin(), 0.0));
+        // Heading along the tropism nothing bends.
+        assert_near(end(&turtle, "|FF"), Vec3::new(0.0, -2.0, 0.0));
+    }
+
+    #[test]
+    fn polygons_are_fans_of_triangles() {
+        let lines = draw(&turtle(), "{.f+f+f}");
+        assert!(lines.lines.is_empty());
+        assert_eq!(lines.triangles.len(), 2);
+        assert_near(lines.triangles[0][0], Vec3::ZERO);
+        assert_near(lines.triangles[1][2], Vec3::NEG_X);
+    }
+
+    #[test]
+    fn palette_colors_follow_the_apostrophe() {
+        let turtle = Turtle {
+            palette: vec![Color::RED, Color::GREEN],
+            ..turtle()
+        };
+        let colors: Vec<Color> = draw(&turtle, "F'F'F'(0)F")
+            .colors
+            .iter()
+            .map(|[start, _]| *start)
+            .collect();
+        // Past the end of the palette the last color sticks.
+        assert_eq!(colors, [Color::RED, Color::GREEN, Color::GREEN, Color::RED]);
+        assert!(draw(&self::turtle(), "F'F").colors.is_empty());
+    }
+
+    #[test]
+    fn gradient_spreads_over_depth() {
+        let gradient = ColorGradient {
+            mode: GradientMode::Depth,
+            ..Default::default()
+        };
+        let turtle = Turtle {
+            gradient: gradient.clone(),
+            ..turtle()
+        };
+        let colors = draw(&turtle, "F[F]").colors;
+        assert_eq!(colors[0], [gradient.stops[0].1; 2]);
+        assert_eq!(colors[1], [gradient.stops[1].1; 2]);
+    }
+}
@@ -0,0 +1,137 @@
+use bevy::{
+    pbr::{ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline},
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_resource::{
+            AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError,
+        },
+    },
+};
+
+use crate::lsys_rendering::{specialize_growth, LineMaterial};
+
+/// `wind.wgsl`, imported by the plant material shaders as
+/// `bevy_lsystems::wind`.
+pub(crate) const WIND_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(87619245513171484930017455390752436860);
+
+/// `swaying_material.wgsl`.
+pub(crate) const SWAYING_MATERIAL_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(65043452778852685570907584701705387562);
+
+/// Wind blowing through the whole garden, copied into the plant materials
+/// whenever it changes.
+#[derive(Resource, Debug, Clone)]
+pub struct Wind {
+    /// Horizontal direction the wind blows towards.
+    pub direction: Vec3,
+    /// How far the tip of a plant one unit tall is blown aside.
+    pub strength: f32,
+}
+
+impl Default for Wind {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::X,
+            strength: 0.05,
+        }
+    }
+}
+
+pub(crate) use uniform::WindUniform;
+
+// `ShaderType` derives layout checks next to the struct that are never
+// called, which only an `allow` on the surrounding module silences.
+#[allow(dead_code)]
+mod uniform {
+    use bevy::{math::Vec3, render::render_resource::ShaderType};
+
+    /// The `Wind` struct of `wind.wgsl`.
+    #[derive(ShaderType, Debug, Clone, Copy, Default)]
+    pub(crate) struct WindUniform {
+        pub(crate) direction: Vec3,
+        pub(crate) strength: f32,
+    }
+}
+
+/// Adds wind sway and growth to the vertex stage of `StandardMaterial`, for
+/// plants drawn as tubes and for their foliage.
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone, Default)]
+pub(crate) struct WindExtension {
+    #[uniform(100)]
+    pub(crate) wind: WindUniform,
+    /// Generations the plant still has to grow, set by
+    /// `animate_plant_growth`.
+    #[uniform(101)]
+    pub(crate) growth_left: f32,
+}
+
+impl MaterialExtension for WindExtension {
+    fn vertex_shader() -> ShaderRef {
+        SWAYING_MATERIAL_SHADER_HANDLE.into()
+    }
+
+    /// Sways and grows the depth, normal and shadow passes along with the
+    /// color pass, so shadows follow the plant.
+    fn prepass_vertex_shader() -> ShaderRef {
+        SWAYING_MATERIAL_SHADER_HANDLE.into()
+    }
+
+    fn deferred_vertex_shader() -> ShaderRef {
+        SWAYING_MATERIAL_SHADER_HANDLE.into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialExtensionPipeline,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialExtensionKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        specialize_growth(descriptor, layout)
+    }
+}
+
+/// `StandardMaterial` bent by the wind, for tubes and foliage.
+pub(crate) type SwayingMaterial = ExtendedMaterial<StandardMaterial, WindExtension>;
+
+/// Copies the wind into every plant material when it changes, and into
+/// materials added since. The shaders animate the gusts with the global
+/// time, so nothing changes from one frame to the next.
+pub fn blow_wind(
+    wind: Res<Wind>,
+    mut line_events: EventReader<AssetEvent<LineMaterial>>,
+    mut swaying_events: EventReader<AssetEvent<SwayingMaterial>>,
+    mut line_materials: ResMut<Assets<LineMaterial>>,
+    mut swaying_materials: ResMut<Assets<SwayingMaterial>>,
+) {
+    let uniform = WindUniform {
+        direction: wind.direction.normalize_or_zero(),
+        strength: wind.strength,
+    };
+    if wind.is_changed() {
+        line_events.clear();
+        swaying_events.clear();
+        for (_, material) in line_materials.iter_mut() {
+            material.wind = uniform;
+        }
+        for (_, material) in swaying_materials.iter_mut() {
+            material.extension.wind = uniform;
+        }
+        return;
+    }
+    for event in line_events.read() {
+        if let AssetEvent::Added { id } = event {
+            if let Some(material) = line_materials.get_mut(*id) {
+                material.wind = uniform;
+            }
+        }
+    }
+    for event in swaying_events.read() {
+        if let AssetEvent::Added { id } = event {
+            if let Some(material) = swaying_materials.get_mut(*id) {
+                material.extension.wind = uniform;
+            }
+        }
+    }
+}